@@ -93,14 +93,14 @@ fn main() {
         });
 
         if let Some(t) = network_time {
-            info!("SNTP timestamp received: {:?}", t);
+            info!("SNTP timestamp received: {:?}", t.timestamp);
         }
 
         let mut timeout = sntp.next_poll(timestamp);
 
-        iface
-            .poll_delay(&sockets, timestamp)
-            .map(|sockets_timeout| timeout = sockets_timeout);
+        if let Some(sockets_timeout) = iface.poll_delay(&sockets, timestamp) {
+            timeout = sockets_timeout;
+        }
 
         phy_wait(fd, Some(timeout)).unwrap_or_else(|e| error!("Wait error: {}", e));
     }
@@ -1,39 +1,615 @@
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::clock::{Clock, ClockMapping};
+use crate::error::{Result, SntpError};
+use crate::filter::{ClockFilter, Sample, SampleSelection, DEFAULT_SAMPLE_COUNT};
+use crate::fixed::{
+    duration_to_log2_secs, log2_secs_to_duration, log2_secs_to_micros, short_to_micros,
+};
 use crate::net::{
     socket::{SocketHandle, SocketSet, UdpSocket, UdpSocketBuffer},
     time::{Duration, Instant},
     wire::{IpAddress, IpEndpoint},
-    {Error, Result},
+    Error,
+};
+use crate::servers::{
+    BlockReason, Exchange, QueryMode, RetryPolicy, ServerList, SwitchReason, FAILOVER_THRESHOLD,
+    MAX_SERVERS,
+};
+use crate::socket::{check_buffers, check_socket};
+use crate::stats::Stats;
+use crate::wire::{
+    field, unpack_flags, LeapIndicator, Packet, ProtocolMode, Repr, Stratum, Timestamp,
+    NTP_VERSION_4,
 };
-use crate::wire::{LeapIndicator, Packet, ProtocolMode, Repr, Stratum, Timestamp};
 
-/// Minimum interval between requests (defaults to one minute)
-const MIN_REQUEST_INTERVAL: Duration = Duration { millis: 60 * 1_000 };
+/// Minimum size, in bytes, of the payload storage of the socket buffers.
+///
+/// This is the size of an SNTP packet without authentication.
+pub const MIN_BUFFER_LEN: usize = field::KEY_IDENTIFIER.start;
 
-/// Maximum interval between requests (defaults to one day)
-const MAX_REQUEST_INTERVAL: Duration = Duration {
-    millis: 24 * 60 * 60 * 1_000,
-};
+/// Default maximum size, in bytes, of the received packets.
+///
+/// This leaves room for a MAC and a few extension fields.
+pub const MAX_PACKET_LEN: usize = 1024;
+
+/// Size, in bytes, of an encoded [`SyncResult`] record.
+pub const SYNC_RESULT_RECORD_LEN: usize = 64;
+
+/// Format version of encoded [`SyncResult`] records.
+const SYNC_RESULT_RECORD_VERSION: u8 = 2;
+
+/// Default minimum poll exponent (2^6 s, about one minute).
+const DEFAULT_MINPOLL: u8 = 6;
+
+/// Default maximum poll exponent (2^16 s, about 18 hours).
+const DEFAULT_MAXPOLL: u8 = 16;
+
+/// Smallest poll exponent allowed by RFC 4330 (2^4 s, the 15 s floor rounded up).
+const MIN_POLL_EXPONENT: u8 = 4;
+
+/// Smallest fixed poll exponent, the lowest `minpoll` accepted in `ntp.conf` (2^3 s).
+const MIN_FIXED_POLL_EXPONENT: u8 = 3;
+
+/// Largest poll exponent allowed by RFC 4330 (2^17 s, about 36 hours).
+const MAX_POLL_EXPONENT: u8 = 17;
+
+/// Initial backoff after a network error.
+const NETWORK_BACKOFF_MIN: Duration = Duration { millis: 1_000 };
+
+/// Time during which replies are collected in concurrent mode.
+const COLLECT_WINDOW: Duration = Duration { millis: 2_000 };
 
-/// Number of seconds between 1970 and Feb 7, 2036 06:28:16 UTC (epoch 1).
-/// Used for NTP to Unix timestamp conversion.
-const DIFF_SEC_1970_2036: u32 = 2085978496;
+/// Oldest protocol version accepted in responses.
+pub(crate) const MIN_VERSION: u8 = 1;
+
+/// Newest protocol version accepted in responses.
+pub(crate) const MAX_VERSION: u8 = 4;
+
+/// Largest stratum value in use, denoting an unsynchronized server.
+const MAX_STRATUM: u8 = 16;
+
+/// Range of precisions accepted with strict validation, as log2 seconds.
+const MIN_SANE_PRECISION: i8 = -32;
+const MAX_SANE_PRECISION: i8 = 10;
+
+/// Range of poll intervals accepted with strict validation, as log2 seconds.
+const MIN_SANE_POLL: u8 = 3;
+const MAX_SANE_POLL: u8 = 17;
+
+/// Default precision of the local clock, as log2 seconds.
+///
+/// This matches the 1 ms resolution of `Instant` (2^-10 s is about 0.98 ms).
+pub(crate) const DEFAULT_PRECISION: i8 = -10;
 
 /// IANA port for SNTP servers.
-const SNTP_PORT: u16 = 123;
+pub(crate) const SNTP_PORT: u16 = 123;
+
+/// Maximum frequency error of the local clock assumed by the synchronization
+/// distance, in parts per million (the RFC 5905 value).
+const MAX_DRIFT_PPM: i64 = 15;
+
+/// Default number of consecutive unanswered requests after which a synchronized
+/// client enters holdover.
+const DEFAULT_HOLDOVER_THRESHOLD: u8 = 3;
+
+/// Leap indicators accepted by default: all but `AlarmCondition`.
+const DEFAULT_ALLOWED_LEAP: u8 = 0b0111;
+
+/// Default maximum number of packets processed by a single poll.
+const DEFAULT_PACKET_BUDGET: usize = 4;
+
+/// Default offset above which the local clock should be stepped rather than slewed.
+const DEFAULT_STEP_THRESHOLD: Duration = Duration { millis: 128 };
+
+/// Synchronization state of the client.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SyncState {
+    /// No request has been sent yet and no valid time is known.
+    Unsynced,
+    /// Requests have been sent, but no valid response has been received yet.
+    Syncing,
+    /// A valid response has been received and the server is still answering.
+    Synced,
+    /// The client was synchronized, but the server stopped answering.
+    Holdover,
+}
+
+/// How requests are scheduled.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PollSchedule {
+    /// Start at the minimum poll interval, double it with each unanswered
+    /// request, and poll at the maximum poll interval once synchronized.
+    /// This is the default.
+    Backoff,
+    /// Poll every `2^fast` seconds until the first valid response, then
+    /// every `2^lazy` seconds, whether the server answers or not.
+    FastThenLazy {
+        /// Poll interval before the first synchronization, as log2 seconds.
+        fast: u8,
+        /// Poll interval after the first synchronization, as log2 seconds.
+        lazy: u8,
+    },
+}
+
+impl Default for PollSchedule {
+    fn default() -> Self {
+        PollSchedule::Backoff
+    }
+}
+
+/// The outcome of a successful synchronization with the server.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SyncResult {
+    /// Unix timestamp (ie. seconds since epoch) of the server transmit time.
+    ///
+    /// This wraps around on Feb 7, 2106: see [`unix_seconds_u64`] for a
+    /// timestamp that doesn't.
+    ///
+    /// [`unix_seconds_u64`]: #method.unix_seconds_u64
+    pub timestamp: u32,
+    /// Unix timestamp of the server transmit time, without wraparound.
+    unix_seconds: u64,
+    /// Server transmit time, as sent in the response.
+    server_timestamp: Timestamp,
+    /// Time the server took to process the request, from its receive
+    /// to its transmit timestamp, in microseconds.
+    processing_delay: i64,
+    /// Time since the server last synchronized, from its reference to its
+    /// transmit timestamp, in microseconds, if it ever did.
+    server_sync_age: Option<i64>,
+    /// Offset of the server clock relative to the local clock, in microseconds.
+    offset: i64,
+    /// Round-trip delay of the exchange, in microseconds.
+    delay: i64,
+    /// Round-trip delay to the primary reference through the server, in microseconds.
+    root_delay: i64,
+    /// Estimated maximum error of the offset, in microseconds.
+    max_error: i64,
+    /// Whether the server reported its clock as unsynchronized.
+    unsynchronized: bool,
+    /// Whether the result was obtained while the client was warming up.
+    provisional: bool,
+    /// Stratum of the server.
+    stratum: u8,
+    /// Whether the response carried a valid MAC.
+    authenticated: bool,
+    /// Address of the server the response came from.
+    server: IpAddress,
+}
+
+impl SyncResult {
+    /// Returns the Unix timestamp of the server transmit time, in seconds.
+    ///
+    /// Unlike [`timestamp`], this doesn't wrap around in 2106. NTP timestamps
+    /// themselves wrap around every 136 years (the first time in 2036): the
+    /// era of the server time is the one closest to the time estimated by the
+    /// client, if it has been synchronized or seeded before, or the one
+    /// ranging from 1968 to 2104 otherwise.
+    ///
+    /// [`timestamp`]: #structfield.timestamp
+    pub fn unix_seconds_u64(&self) -> u64 {
+        self.unix_seconds
+    }
+
+    /// Returns the server transmit time (`xmit_timestamp` field) as sent in the
+    /// response, without conversion.
+    ///
+    /// This is useful to feed other NTP-aware components, or to investigate
+    /// era issues. Results decoded from a record only retain the 16 most
+    /// significant bits of the fraction (see [`encode`]).
+    ///
+    /// [`encode`]: #method.encode
+    pub fn server_ntp_timestamp(&self) -> Timestamp {
+        self.server_timestamp
+    }
+
+    /// Returns the magnitude of the offset of the server clock relative to the local clock.
+    ///
+    /// The offset is the amount of time to add to a local `Instant` to obtain
+    /// the corresponding Unix time. See [`offset_is_negative`] for its sign.
+    ///
+    /// [`offset_is_negative`]: #method.offset_is_negative
+    pub fn offset(&self) -> Duration {
+        Duration::from_millis(self.offset.unsigned_abs() / 1_000)
+    }
+
+    /// Returns `true` if the local clock is ahead of the server clock.
+    pub fn offset_is_negative(&self) -> bool {
+        self.offset < 0
+    }
+
+    /// Returns the offset of the server clock relative to the local clock, in
+    /// microseconds. The offset is negative if the local clock is ahead.
+    pub fn offset_micros(&self) -> i64 {
+        self.offset
+    }
+
+    /// Returns the round-trip delay of the exchange, excluding the server processing time.
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay.unsigned_abs() / 1_000)
+    }
+
+    /// Returns the round-trip delay of the exchange, excluding the server
+    /// processing time, in microseconds. The delay is never negative.
+    pub fn delay_micros(&self) -> i64 {
+        self.delay
+    }
+
+    /// Returns the round-trip delay to the primary reference through the
+    /// server, in microseconds: the root delay of the server (`root_delay`
+    /// field) plus the round-trip delay of the exchange.
+    ///
+    /// This is the root delay to advertise when relaying the time of the
+    /// server, see [`Client::root_delay`].
+    ///
+    /// [`Client::root_delay`]: struct.Client.html#method.root_delay
+    pub fn root_delay_micros(&self) -> i64 {
+        self.root_delay
+    }
+
+    /// Returns the offset corrected for an asymmetric path, in microseconds.
+    ///
+    /// The offset is computed assuming that the request and the response take
+    /// the same time to travel. When they don't, the offset is biased by half
+    /// the difference, which is at most half the round-trip delay. `asymmetry`
+    /// is the time the request takes to reach the server minus the time the
+    /// response takes to come back, in microseconds: it is positive when the
+    /// outbound path is the slower one, e.g. on links with a slow uplink.
+    ///
+    /// The asymmetry can't be measured by the client itself: it must be known
+    /// from the network setup, or estimated by other means. Comparing the
+    /// round-trip delay to its minimum (see [`Client::min_delay_micros`]) shows
+    /// how much queueing a sample suffered, which is often the main source of
+    /// asymmetry, but not in which direction.
+    ///
+    /// [`Client::min_delay_micros`]: struct.Client.html#method.min_delay_micros
+    pub fn offset_asymmetry_corrected(&self, asymmetry: i64) -> i64 {
+        self.offset - asymmetry / 2
+    }
+
+    /// Returns an upper bound on the error of the offset, rounded up to the millisecond.
+    ///
+    /// This is the NTP synchronization distance, the sum of:
+    ///
+    /// - the root dispersion of the server (`root_dispersion` field), i.e. the
+    ///   error it accumulated relative to its primary reference;
+    /// - half the root delay of the server (`root_delay` field), the round trip
+    ///   to its primary reference;
+    /// - half the round-trip delay of the exchange, as returned by [`delay`];
+    /// - the precision of the server clock (`precision` field) and of the local
+    ///   clock, as set with [`Client::set_precision`].
+    ///
+    /// [`delay`]: #method.delay
+    /// [`Client::set_precision`]: struct.Client.html#method.set_precision
+    pub fn max_error(&self) -> Duration {
+        Duration::from_millis((self.max_error.max(0) as u64 + 999) / 1_000)
+    }
+
+    /// Returns `true` if the server reported its clock as unsynchronized.
+    ///
+    /// Such responses are only accepted if enabled with
+    /// [`Client::set_accept_unsynchronized`], and their time cannot be trusted.
+    ///
+    /// [`Client::set_accept_unsynchronized`]: struct.Client.html#method.set_accept_unsynchronized
+    pub fn is_unsynchronized(&self) -> bool {
+        self.unsynchronized
+    }
+
+    /// Returns `true` if the result was obtained before the client collected
+    /// enough samples to consider itself synchronized.
+    ///
+    /// See [`Client::set_min_samples`].
+    ///
+    /// [`Client::set_min_samples`]: struct.Client.html#method.set_min_samples
+    pub fn is_provisional(&self) -> bool {
+        self.provisional
+    }
+
+    /// Returns the stratum of the server, e.g. 1 for a primary server.
+    pub fn stratum(&self) -> u8 {
+        self.stratum
+    }
+
+    /// Returns the address of the server the response came from.
+    ///
+    /// Records don't include it: results read back with [`decode`] report
+    /// an unspecified address.
+    ///
+    /// [`decode`]: #method.decode
+    pub fn server(&self) -> IpAddress {
+        self.server
+    }
+
+    /// Returns `true` if the response carried a valid MAC.
+    ///
+    /// The client doesn't support symmetric key authentication yet: requests
+    /// carry no MAC, and the MACs of responses aren't verified, so this is
+    /// always `false` for results of the client. See
+    /// [`Client::set_require_auth`].
+    ///
+    /// [`Client::set_require_auth`]: struct.Client.html#method.set_require_auth
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Returns the time the server took to process the request
+    /// (`T3 - T2`, i.e. transmit minus receive timestamp), in microseconds.
+    ///
+    /// A large or negative value hints at a misbehaving or heavily loaded server.
+    pub fn processing_delay_micros(&self) -> i64 {
+        self.processing_delay
+    }
+
+    /// Returns how long ago the server itself last synchronized to its time
+    /// source (`xmit - ref`, i.e. transmit minus reference timestamp), rounded
+    /// down to the millisecond.
+    ///
+    /// An age of hours or days hints at a server that lost its upstream time
+    /// source, see [`Client::set_max_server_sync_age`]. Returns `None` if the
+    /// reference timestamp is zero, i.e. the server never synchronized.
+    ///
+    /// [`Client::set_max_server_sync_age`]: struct.Client.html#method.set_max_server_sync_age
+    pub fn server_sync_age(&self) -> Option<Duration> {
+        self.server_sync_age
+            .map(|age| Duration::from_millis(age.max(0) as u64 / 1_000))
+    }
+
+    /// Returns a key ordering results by quality, the best first.
+    ///
+    /// Results are ordered by round-trip delay, then by estimated maximum
+    /// error, since the offset measured over a shorter round trip is less
+    /// affected by asymmetric network delays. This allows selecting the best
+    /// of several results with standard library calls:
+    ///
+    /// ```rust,ignore
+    /// let best = results.iter().min_by_key(|result| result.quality_key());
+    /// results.sort_unstable_by_key(SyncResult::quality_key);
+    /// ```
+    pub fn quality_key(&self) -> (i64, i64) {
+        (self.delay, self.max_error)
+    }
+
+    /// Encodes the result into a fixed-size record of [`SYNC_RESULT_RECORD_LEN`] bytes.
+    ///
+    /// All fields are big-endian:
+    ///
+    /// | Offset | Size | Field                                    |
+    /// |--------|------|------------------------------------------|
+    /// | 0      | 1    | Record format version (currently 2)      |
+    /// | 1      | 1    | Flags, bit 0: unsynchronized server,     |
+    /// |        |      | bit 1: provisional result,               |
+    /// |        |      | bits 2-6: stratum,                       |
+    /// |        |      | bit 7: authenticated response            |
+    /// | 2      | 2    | Server NTP time, fraction (upper 16 bits)|
+    /// | 4      | 4    | Unix timestamp, seconds (`u32`)          |
+    /// | 8      | 8    | Offset, microseconds (`i64`)             |
+    /// | 16     | 8    | Round-trip delay, microseconds (`i64`)   |
+    /// | 24     | 8    | Processing delay, microseconds (`i64`)   |
+    /// | 32     | 8    | Maximum error, microseconds (`i64`)      |
+    /// | 40     | 8    | Unix timestamp, seconds (`u64`)          |
+    /// | 48     | 8    | Root delay, microseconds (`i64`)         |
+    /// | 56     | 8    | Server sync age, microseconds (`i64`),   |
+    /// |        |      | -1 if the server never synchronized      |
+    ///
+    /// Returns the number of bytes written, or `Err(SntpError::BufferTooSmall)`
+    /// if `buf` is shorter than a record.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize> {
+        let buf = buf
+            .get_mut(..SYNC_RESULT_RECORD_LEN)
+            .ok_or(SntpError::BufferTooSmall)?;
+        buf[0] = SYNC_RESULT_RECORD_VERSION;
+        buf[1] = u8::from(self.unsynchronized)
+            | u8::from(self.provisional) << 1
+            | self.stratum.min(MAX_STRATUM) << 2
+            | u8::from(self.authenticated) << 7;
+        NetworkEndian::write_u16(&mut buf[2..4], (self.server_timestamp.frac >> 16) as u16);
+        NetworkEndian::write_u32(&mut buf[4..8], self.timestamp);
+        NetworkEndian::write_i64(&mut buf[8..16], self.offset);
+        NetworkEndian::write_i64(&mut buf[16..24], self.delay);
+        NetworkEndian::write_i64(&mut buf[24..32], self.processing_delay);
+        NetworkEndian::write_i64(&mut buf[32..40], self.max_error);
+        NetworkEndian::write_u64(&mut buf[40..48], self.unix_seconds);
+        NetworkEndian::write_i64(&mut buf[48..56], self.root_delay);
+        NetworkEndian::write_i64(&mut buf[56..64], self.server_sync_age.unwrap_or(-1));
+        Ok(SYNC_RESULT_RECORD_LEN)
+    }
+
+    /// Decodes a record produced by [`encode`].
+    ///
+    /// Returns `Err(SntpError::BufferTooSmall)` if `buf` is shorter than a record,
+    /// or `Err(SntpError::UnsupportedVersion)` if the record format is unknown.
+    ///
+    /// [`encode`]: #method.encode
+    pub fn decode(buf: &[u8]) -> Result<SyncResult> {
+        let buf = buf
+            .get(..SYNC_RESULT_RECORD_LEN)
+            .ok_or(SntpError::BufferTooSmall)?;
+        if buf[0] != SYNC_RESULT_RECORD_VERSION {
+            return Err(SntpError::UnsupportedVersion(buf[0]));
+        }
+        let unix_seconds = NetworkEndian::read_u64(&buf[40..48]);
+        let server_timestamp = Timestamp {
+            sec: Timestamp::from_unix_micros(unix_seconds as i64 * 1_000_000).sec,
+            frac: u32::from(NetworkEndian::read_u16(&buf[2..4])) << 16,
+        };
+        Ok(SyncResult {
+            timestamp: NetworkEndian::read_u32(&buf[4..8]),
+            unix_seconds,
+            server_timestamp,
+            offset: NetworkEndian::read_i64(&buf[8..16]),
+            delay: NetworkEndian::read_i64(&buf[16..24]),
+            root_delay: NetworkEndian::read_i64(&buf[48..56]),
+            server_sync_age: Some(NetworkEndian::read_i64(&buf[56..64])).filter(|&age| age >= 0),
+            processing_delay: NetworkEndian::read_i64(&buf[24..32]),
+            max_error: NetworkEndian::read_i64(&buf[32..40]),
+            unsynchronized: buf[1] & 0x01 != 0,
+            provisional: buf[1] & 0x02 != 0,
+            stratum: (buf[1] >> 2) & 0x1f,
+            authenticated: buf[1] & 0x80 != 0,
+            server: IpAddress::Unspecified,
+        })
+    }
+}
 
 /// SNTPv4 client.
 ///
 /// You must call `Client::poll()` after `Interface::poll()` to send
 /// and receive SNTP packets.
-pub struct Client {
-    udp_handle: SocketHandle,
-    ntp_server: IpAddress,
+///
+/// Applications using another UDP stack can create the client with
+/// [`without_socket`], and drive it with [`process_response`] and
+/// [`poll_requests`] instead.
+///
+/// The client retains the `N` most recent samples, which defaults to
+/// [`DEFAULT_SAMPLE_COUNT`]. Memory-constrained devices may want to use a
+/// smaller value, e.g. `Client<4>`, while others may keep a longer history.
+///
+/// The client may also hold a [`Clock`] of type `C`, to be polled without
+/// passing the current time, see [`set_clock`]. It defaults to a function
+/// pointer, e.g. `Instant::now`; tests may use a closure reading a fake time.
+///
+/// Only unicast mode is supported: time is only accepted from the configured
+/// servers (see [`add_server`]), in reply to a request sent to them. Packets
+/// from any other source, including broadcast and multicast packets, are
+/// dropped and counted in [`Stats::wrong_source`]. Sources can be further
+/// restricted with [`set_trusted_sources`].
+///
+/// [`add_server`]: #method.add_server
+/// [`Stats::wrong_source`]: struct.Stats.html#structfield.wrong_source
+/// [`set_trusted_sources`]: #method.set_trusted_sources
+/// [`DEFAULT_SAMPLE_COUNT`]: constant.DEFAULT_SAMPLE_COUNT.html
+/// [`Clock`]: trait.Clock.html
+/// [`set_clock`]: #method.set_clock
+/// [`without_socket`]: #method.without_socket
+/// [`process_response`]: #method.process_response
+/// [`poll_requests`]: #method.poll_requests
+pub struct Client<const N: usize = DEFAULT_SAMPLE_COUNT, C = fn() -> Instant> {
+    /// Socket of the client, unless it is driven by another transport.
+    udp_handle: Option<SocketHandle>,
+    /// Additional socket on which responses are received, if any.
+    rx_handle: Option<SocketHandle>,
+    /// Configured servers.
+    servers: ServerList,
+    /// How the servers are queried.
+    query_mode: QueryMode,
+    /// Which server unanswered requests are retried with.
+    retry_policy: RetryPolicy,
+    /// When to stop waiting for replies, in concurrent mode.
+    collect_until: Option<Instant>,
+    /// Counters of discarded packets.
+    stats: Stats,
     /// When to send next request.
     next_request: Instant,
     /// Current timeout interval.
     curr_interval: Duration,
+    /// How requests are scheduled.
+    schedule: PollSchedule,
+    /// Minimum poll interval, as log2 seconds.
+    minpoll: u8,
+    /// Maximum poll interval, as log2 seconds.
+    maxpoll: u8,
+    /// Poll interval advertised by the server in its last accepted response, as log2 seconds.
+    server_poll: Option<u8>,
+    /// DSCP value requested for outgoing packets, if any.
+    dscp: Option<u8>,
+    /// Precision of the local clock, as log2 seconds.
+    precision: i8,
+    /// Resolution reported offsets and delays are rounded to, in microseconds.
+    resolution: Option<i64>,
+    /// Current synchronization state.
+    state: SyncState,
+    /// Whether a request is waiting for a response.
+    outstanding: bool,
+    /// Number of consecutive requests that went unanswered.
+    unanswered: u8,
+    /// Time the last sample was accepted.
+    last_sync: Option<Instant>,
+    /// Maximum error of the last accepted sample, in microseconds.
+    last_max_error: i64,
+    /// Root delay of the last accepted sample, in microseconds.
+    last_root_delay: i64,
+    /// Stratum of the server of the last accepted sample.
+    last_stratum: u8,
+    /// Address of the server of the last accepted sample.
+    last_server: IpAddress,
+    /// Number of unanswered requests after which the client enters holdover.
+    holdover_threshold: u8,
+    /// Number of samples required before the client is synchronized.
+    min_samples: u8,
+    /// Number of samples accepted, up to `min_samples`.
+    accepted: u8,
+    /// Offset above which the local clock should be stepped.
+    step_threshold: Duration,
+    /// Step threshold applied to the samples of primary servers, if different.
+    primary_step_threshold: Option<Duration>,
+    /// Leap indicator advertised in requests when synchronized, if relaying time.
+    leap_indicator: Option<LeapIndicator>,
+    /// Leap indicators accepted in responses, one bit per value.
+    allowed_leap: u8,
+    /// Whether the socket is bound automatically.
+    auto_bind: bool,
+    /// Whether the network link is up.
+    link_up: bool,
+    /// Number of consecutive network errors.
+    network_errors: u8,
+    /// Backoff applied after the last network error, if any.
+    network_backoff: Option<Duration>,
+    /// No request is sent before this time, following a network error.
+    network_retry_at: Option<Instant>,
+    /// State of the pseudo-random generator used for jitter.
+    rng_state: u32,
+    /// Maximum accepted server processing delay, if any.
+    max_processing_delay: Option<Duration>,
+    /// Maximum time since the server last synchronized, if any.
+    max_server_sync_age: Option<Duration>,
+    /// Whether responses with out-of-range or inconsistent fields are discarded.
+    strict_validation: bool,
+    /// Whether responses from misconfigured servers are discarded.
+    loop_detection: bool,
+    /// Whether responses without a valid MAC are discarded.
+    require_auth: bool,
+    /// Addresses packets are accepted from, or all `None` to accept any.
+    trusted_sources: [Option<IpAddress>; MAX_SERVERS],
+    /// Whether requests are tagged with a sequence number.
+    sequence_tagging: bool,
+    /// Sequence number of the last request.
+    sequence: u8,
+    /// Maximum number of packets processed by a single poll.
+    packet_budget: usize,
+    /// Maximum size of the received packets.
+    max_packet_len: usize,
+    /// Which offset is reported for each accepted response.
+    sample_selection: SampleSelection,
+    /// Maximum difference between the server time and the local estimate, if any.
+    acceptance_window: Option<Duration>,
+    /// When the last request was sent.
+    last_request: Option<Instant>,
+    /// No request is sent before this time, following a `RATE` kiss-o'-death.
+    rate_limited_until: Option<Instant>,
+    /// Most recent samples.
+    filter: ClockFilter<N>,
+    /// Last response to one of our requests.
+    last_repr: Option<Repr>,
+    /// Last request sent, as emitted.
+    last_raw_request: Option<[u8; MIN_BUFFER_LEN]>,
+    /// Whether the samples hold a seeded estimate rather than measurements.
+    seeded: bool,
+    /// Jump of the offset detected with the last accepted sample, in microseconds.
+    external_step: Option<i64>,
+    /// Source of the local time, if any.
+    clock: Option<C>,
+    /// Hook invoked on each accepted sample.
+    on_sync: Option<fn(&SyncResult)>,
+    /// Hook invoked on each failure.
+    on_error: Option<fn(&SntpError)>,
+    /// Hook invoked when the active server changes.
+    on_switch: Option<fn(IpAddress, IpAddress, SwitchReason)>,
 }
 
+/// An SNTPv4 client retaining the default number of samples.
+pub type DefaultClient = Client<DEFAULT_SAMPLE_COUNT>;
+
 impl Client {
     /// Create a new SNTPv4 client performing requests to the specified server.
     ///
@@ -70,6 +646,23 @@ impl Client {
     ///     Instant::from_secs(0),
     /// );
     /// ```
+    ///
+    /// # Panics
+    ///
+    /// This function panics if either buffer has no packet metadata slot, or
+    /// payload storage smaller than [`MIN_BUFFER_LEN`], since the client could
+    /// never send or receive a packet. Use [`try_new`] to handle these cases.
+    ///
+    /// Like `SocketSet::add`, it also panics if `sockets` uses fixed-size
+    /// storage and has no free slot.
+    ///
+    /// A server address that can't be the one of a unicast server, e.g. a
+    /// broadcast or multicast address, doesn't panic but is blocked, see
+    /// [`add_server`].
+    ///
+    /// [`try_new`]: #method.try_new
+    /// [`MIN_BUFFER_LEN`]: constant.MIN_BUFFER_LEN.html
+    /// [`add_server`]: #method.add_server
     pub fn new<'a, 'b, 'c>(
         sockets: &mut SocketSet<'a, 'b, 'c>,
         rx_buffer: UdpSocketBuffer<'b, 'c>,
@@ -77,137 +670,4461 @@ impl Client {
         ntp_server: IpAddress,
         now: Instant,
     ) -> Self
+    where
+        'b: 'c,
+    {
+        Self::new_sized(sockets, rx_buffer, tx_buffer, ntp_server, now)
+    }
+
+    /// Create a new SNTPv4 client, checking the buffers.
+    ///
+    /// This is the same as [`new`], but returns `Err(SntpError::NoPacketMetadata)`
+    /// or `Err(SntpError::BufferTooSmall)` if the buffers are unusable, in which
+    /// case the socket set is left unchanged.
+    ///
+    /// # Panics
+    ///
+    /// This function still panics if `sockets` uses fixed-size storage and has
+    /// no free slot, since `smoltcp` can't report it. To handle a full set, add
+    /// the socket yourself and use [`with_socket`].
+    ///
+    /// [`new`]: #method.new
+    /// [`with_socket`]: #method.with_socket
+    pub fn try_new<'a, 'b, 'c>(
+        sockets: &mut SocketSet<'a, 'b, 'c>,
+        rx_buffer: UdpSocketBuffer<'b, 'c>,
+        tx_buffer: UdpSocketBuffer<'b, 'c>,
+        ntp_server: IpAddress,
+        now: Instant,
+    ) -> Result<Self>
+    where
+        'b: 'c,
+    {
+        Self::try_new_sized(sockets, rx_buffer, tx_buffer, ntp_server, now)
+    }
+}
+
+impl<const N: usize, C> Client<N, C> {
+    /// Create a new SNTPv4 client retaining `N` samples.
+    ///
+    /// This is the same as [`new`], for clients with a non-default number of
+    /// samples, e.g. `Client::<4>::new_sized(...)`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `N` is zero, or if the buffers are unusable
+    /// (see [`new`]).
+    ///
+    /// [`new`]: #method.new
+    pub fn new_sized<'a, 'b, 'c>(
+        sockets: &mut SocketSet<'a, 'b, 'c>,
+        rx_buffer: UdpSocketBuffer<'b, 'c>,
+        tx_buffer: UdpSocketBuffer<'b, 'c>,
+        ntp_server: IpAddress,
+        now: Instant,
+    ) -> Self
+    where
+        'b: 'c,
+    {
+        match Self::try_new_sized(sockets, rx_buffer, tx_buffer, ntp_server, now) {
+            Ok(client) => client,
+            Err(err) => panic!("invalid SNTP socket buffers: {}", err),
+        }
+    }
+
+    /// Create a new SNTPv4 client retaining `N` samples, checking the buffers.
+    ///
+    /// This is the same as [`try_new`], for clients with a non-default number
+    /// of samples.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `N` is zero, or if `sockets` is full (see
+    /// [`try_new`]).
+    ///
+    /// [`try_new`]: #method.try_new
+    pub fn try_new_sized<'a, 'b, 'c>(
+        sockets: &mut SocketSet<'a, 'b, 'c>,
+        rx_buffer: UdpSocketBuffer<'b, 'c>,
+        tx_buffer: UdpSocketBuffer<'b, 'c>,
+        ntp_server: IpAddress,
+        now: Instant,
+    ) -> Result<Self>
     where
         'b: 'c,
     {
         let socket = UdpSocket::new(rx_buffer, tx_buffer);
+        check_buffers(&socket)?;
         let udp_handle = sockets.add(socket);
 
+        Ok(Self::from_handle(Some(udp_handle), ntp_server, now))
+    }
+
+    /// Create a new SNTPv4 client using a UDP socket already added to `sockets`.
+    ///
+    /// This allows applications to manage the socket set themselves, e.g. to
+    /// share it with other protocols.
+    ///
+    /// Returns `Err(SntpError::InvalidSocket)` if `udp_handle` does not refer
+    /// to a UDP socket of `sockets`, and `Err(SntpError::BufferTooSmall)` if its
+    /// buffers are smaller than [`MIN_BUFFER_LEN`] or
+    /// `Err(SntpError::NoPacketMetadata)` if they have no packet metadata slot.
+    ///
+    /// [`MIN_BUFFER_LEN`]: constant.MIN_BUFFER_LEN.html
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `N` is zero.
+    pub fn with_socket(
+        sockets: &SocketSet,
+        udp_handle: SocketHandle,
+        ntp_server: IpAddress,
+        now: Instant,
+    ) -> Result<Self> {
+        check_socket(sockets, udp_handle)?;
+
+        Ok(Self::from_handle(Some(udp_handle), ntp_server, now))
+    }
+
+    /// Create a new SNTPv4 client without a socket, for use with another transport.
+    ///
+    /// The client must then be driven with [`process_response`] and
+    /// [`poll_requests`]: [`poll`] returns `Err(SntpError::InvalidSocket)`.
+    ///
+    /// [`process_response`]: #method.process_response
+    /// [`poll_requests`]: #method.poll_requests
+    /// [`poll`]: #method.poll
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `N` is zero.
+    pub fn without_socket(ntp_server: IpAddress, now: Instant) -> Self {
+        Self::from_handle(None, ntp_server, now)
+    }
+
+    /// Create a new SNTPv4 client around an existing UDP socket handle, if any.
+    fn from_handle(udp_handle: Option<SocketHandle>, ntp_server: IpAddress, now: Instant) -> Self {
         net_trace!("SNTP initialised");
+        if !ntp_server.is_unicast() {
+            net_debug!("SNTP invalid server address {}, blocking it", ntp_server);
+        }
 
         Client {
             udp_handle,
-            ntp_server,
+            rx_handle: None,
+            servers: ServerList::new(ntp_server),
+            query_mode: QueryMode::Failover,
+            retry_policy: RetryPolicy::SameServer,
+            collect_until: None,
+            stats: Stats::default(),
             next_request: now,
-            curr_interval: MIN_REQUEST_INTERVAL,
+            last_sync: None,
+            last_max_error: 0,
+            last_root_delay: 0,
+            last_stratum: 0,
+            last_server: IpAddress::Unspecified,
+            curr_interval: poll_interval(DEFAULT_MINPOLL),
+            schedule: PollSchedule::Backoff,
+            minpoll: DEFAULT_MINPOLL,
+            maxpoll: DEFAULT_MAXPOLL,
+            server_poll: None,
+            dscp: None,
+            precision: DEFAULT_PRECISION,
+            resolution: None,
+            state: SyncState::Unsynced,
+            outstanding: false,
+            unanswered: 0,
+            holdover_threshold: DEFAULT_HOLDOVER_THRESHOLD,
+            min_samples: 1,
+            accepted: 0,
+            step_threshold: DEFAULT_STEP_THRESHOLD,
+            primary_step_threshold: None,
+            leap_indicator: None,
+            allowed_leap: DEFAULT_ALLOWED_LEAP,
+            auto_bind: true,
+            link_up: true,
+            network_errors: 0,
+            network_backoff: None,
+            network_retry_at: None,
+            rng_state: (micros(now) as u32) | 1,
+            max_processing_delay: None,
+            max_server_sync_age: None,
+            strict_validation: false,
+            loop_detection: true,
+            require_auth: false,
+            trusted_sources: [None; MAX_SERVERS],
+            sequence_tagging: false,
+            sequence: 0,
+            packet_budget: DEFAULT_PACKET_BUDGET,
+            max_packet_len: MAX_PACKET_LEN,
+            sample_selection: SampleSelection::Latest,
+            acceptance_window: None,
+            last_request: None,
+            rate_limited_until: None,
+            filter: ClockFilter::new(),
+            last_repr: None,
+            last_raw_request: None,
+            seeded: false,
+            external_step: None,
+            clock: None,
+            on_sync: None,
+            on_error: None,
+            on_switch: None,
         }
     }
 
-    /// Returns the duration until the next packet request.
+    /// Sets the clock used by [`poll_now`] to obtain the current time.
     ///
-    /// Useful for suspending execution after polling.
-    pub fn next_poll(&self, now: Instant) -> Duration {
-        self.next_request - now
+    /// The type of the clock is a parameter of the client, so that it can
+    /// borrow e.g. the fake time of a test.
+    ///
+    /// [`poll_now`]: #method.poll_now
+    pub fn set_clock(&mut self, clock: Option<C>) {
+        self.clock = clock;
     }
 
-    /// Processes incoming packets, and sends SNTP requests when timeouts expire.
+    /// Same as [`poll`], using the time given by the clock of the client.
     ///
-    /// If a valid response is received, the Unix timestamp (ie. seconds since
-    /// epoch) corresponding to the received NTP timestamp is returned.
-    pub fn poll(&mut self, sockets: &mut SocketSet, now: Instant) -> Result<Option<u32>> {
-        let mut socket = sockets.get::<UdpSocket>(self.udp_handle);
+    /// Returns `Err(SntpError::NoClock)` if no clock has been set with [`set_clock`].
+    ///
+    /// [`poll`]: #method.poll
+    /// [`set_clock`]: #method.set_clock
+    pub fn poll_now(&mut self, sockets: &mut SocketSet) -> Result<Option<SyncResult>>
+    where
+        C: Clock,
+    {
+        let now = self.clock.as_ref().ok_or(SntpError::NoClock)?.now();
+        self.poll(sockets, now)
+    }
 
-        // Bind the socket if necessary
-        if !socket.is_open() {
-            socket.bind(IpEndpoint {
-                addr: IpAddress::Unspecified,
-                port: SNTP_PORT,
-            })?;
-        }
+    /// Registers a function to be called on each accepted sample.
+    ///
+    /// The function is invoked from within [`poll`] with the same result that
+    /// `poll` returns. Pass `None` to remove a previously registered function.
+    ///
+    /// [`poll`]: #method.poll
+    pub fn set_on_sync(&mut self, on_sync: Option<fn(&SyncResult)>) {
+        self.on_sync = on_sync;
+    }
 
-        // Process incoming packets
-        let timestamp = match socket.recv() {
-            Ok((payload, _)) => self.receive(payload),
-            Err(Error::Exhausted) => None,
-            Err(e) => return Err(e),
-        };
+    /// Registers a function to be called on each failure.
+    ///
+    /// The function is invoked from within [`poll`] for every error it returns,
+    /// for every received packet it skips because of an error, as well as for
+    /// kiss-o'-death responses, which are reported as
+    /// [`SntpError::KissOfDeath`]. Pass `None` to remove a previously
+    /// registered function.
+    ///
+    /// [`poll`]: #method.poll
+    /// [`SntpError::KissOfDeath`]: enum.SntpError.html#variant.KissOfDeath
+    pub fn set_on_error(&mut self, on_error: Option<fn(&SntpError)>) {
+        self.on_error = on_error;
+    }
 
-        match timestamp {
-            Some(ts) => {
-                // A valid timestamp was received.
-                // Increase the request interval to its maximum and return the timestamp.
-                self.next_request = now + MAX_REQUEST_INTERVAL;
-                Ok(Some(ts))
-            }
-            None if socket.can_send() && now >= self.next_request => {
-                // The timeout has expired.
-                // Send a request, set the timeout and increment interval using exponential backoff.
-                self.request(&mut *socket)?;
-                self.next_request = now + self.curr_interval;
-                self.curr_interval = MAX_REQUEST_INTERVAL.min(self.curr_interval * 2);
-                Ok(None)
-            }
-            None => Ok(None),
-        }
+    /// Registers a function to be called when the client switches to another
+    /// server, in [`QueryMode::Failover`].
+    ///
+    /// The function is invoked from within [`poll`], right before a request is
+    /// sent to the new server, with the addresses of the previous and the new
+    /// server and the reason of the switch. Pass `None` to remove a previously
+    /// registered function.
+    ///
+    /// [`poll`]: #method.poll
+    /// [`QueryMode::Failover`]: enum.QueryMode.html#variant.Failover
+    pub fn set_on_switch(&mut self, on_switch: Option<fn(IpAddress, IpAddress, SwitchReason)>) {
+        self.on_switch = on_switch;
     }
 
-    /// Processes a response from the SNTP server.
-    fn receive(&mut self, data: &[u8]) -> Option<u32> {
-        let sntp_packet = match Packet::new_checked(data) {
-            Ok(sntp_packet) => sntp_packet,
-            Err(e) => {
-                net_debug!("SNTP invalid pkt: {:?}", e);
-                return None;
-            }
-        };
-        let sntp_repr = match Repr::parse(&sntp_packet) {
-            Ok(sntp_repr) => sntp_repr,
-            Err(e) => {
-                net_debug!("SNTP error parsing pkt: {:?}", e);
-                return None;
-            }
-        };
+    /// Returns an iterator over the most recent samples, from the oldest to the newest.
+    ///
+    /// At most `N` samples are retained.
+    pub fn samples(&self) -> impl Iterator<Item = &Sample> {
+        self.filter.iter()
+    }
 
-        if sntp_repr.protocol_mode != ProtocolMode::Server {
-            net_debug!(
-                "Invalid mode in SNTP response: {:?}",
-                sntp_repr.protocol_mode
-            );
-            return None;
-        }
-        if sntp_repr.stratum == Stratum::KissOfDeath {
-            net_debug!("SNTP kiss o' death received, doing nothing");
-            return None;
-        }
+    /// Returns the last response received to one of the requests of the client.
+    ///
+    /// This gives access to all the fields of the packet, for diagnostic purposes.
+    /// The response may have been discarded afterwards, e.g. if it was a
+    /// kiss-o'-death packet or came from an unsynchronized server.
+    pub fn last_repr(&self) -> Option<&Repr> {
+        self.last_repr.as_ref()
+    }
 
-        // Perform conversion from NTP timestamp to Unix timestamp
-        let timestamp = sntp_repr
-            .xmit_timestamp
-            .sec
-            .wrapping_add(DIFF_SEC_1970_2036);
-
-        Some(timestamp)
-    }
-
-    /// Sends a request to the configured SNTP ntp_server.
-    fn request(&mut self, socket: &mut UdpSocket) -> Result<()> {
-        let sntp_repr = Repr {
-            leap_indicator: LeapIndicator::NoWarning,
-            version: 4,
-            protocol_mode: ProtocolMode::Client,
-            stratum: Stratum::KissOfDeath,
-            poll_interval: 0,
-            precision: 0,
-            root_delay: 0,
-            root_dispersion: 0,
-            ref_identifier: [0, 0, 0, 0],
-            ref_timestamp: Timestamp { sec: 0, frac: 0 },
-            orig_timestamp: Timestamp { sec: 0, frac: 0 },
-            recv_timestamp: Timestamp { sec: 0, frac: 0 },
-            xmit_timestamp: Timestamp { sec: 0, frac: 0 },
-        };
+    /// Returns the bytes of the last request sent, exactly as put on the wire.
+    ///
+    /// This is meant for diagnostic purposes, e.g. to compare the requests to
+    /// a packet capture when a server rejects them.
+    pub fn last_raw_request(&self) -> Option<&[u8]> {
+        self.last_raw_request.as_ref().map(|packet| &packet[..])
+    }
 
-        let endpoint = IpEndpoint {
-            addr: self.ntp_server,
-            port: SNTP_PORT,
-        };
+    /// Returns the counters of the packets discarded by the client.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
 
-        net_trace!("SNTP send request to {}: {:?}", endpoint, sntp_repr);
+    /// Adds a fallback server.
+    ///
+    /// By default, requests are sent to a single server at a time. When the
+    /// active server stops answering or is blocked, the client switches to the
+    /// next server, in the order they were added. See [`set_query_mode`] to
+    /// query all servers at once instead. Adding a server twice has no effect.
+    ///
+    /// Returns `Err(SntpError::TooManyServers)` if [`MAX_SERVERS`] servers
+    /// are already configured.
+    ///
+    /// Returns `Err(SntpError::InvalidAddress)` if `addr` can't be the address
+    /// of a unicast server, i.e. it is the unspecified, the broadcast or a
+    /// multicast address. The server is added nonetheless, but blocked with
+    /// [`BlockReason::InvalidAddress`]: call [`unblock_server`] to use it
+    /// anyway. The server passed to the constructor is blocked the same way.
+    ///
+    /// [`MAX_SERVERS`]: constant.MAX_SERVERS.html
+    /// [`set_query_mode`]: #method.set_query_mode
+    /// [`BlockReason::InvalidAddress`]: enum.BlockReason.html#variant.InvalidAddress
+    /// [`unblock_server`]: #method.unblock_server
+    pub fn add_server(&mut self, addr: IpAddress) -> Result<()> {
+        if !self.servers.add(addr) {
+            return Err(SntpError::TooManyServers);
+        }
+        match self.block_reason(addr) {
+            Some(BlockReason::InvalidAddress) => {
+                net_debug!("SNTP invalid server address {}", addr);
+                Err(SntpError::InvalidAddress)
+            }
+            _ => Ok(()),
+        }
+    }
 
-        let mut packet = socket.send(sntp_repr.buffer_len(), endpoint)?;
-        let mut sntp_packet = Packet::new_unchecked(&mut packet);
-        sntp_repr.emit(&mut sntp_packet)?;
+    /// Sets how the configured servers are queried.
+    ///
+    /// In [`QueryMode::Concurrent`], each poll cycle sends a request to every
+    /// server that isn't blocked, and combines the replies received within two
+    /// seconds by taking the one with the median offset. The transmit buffer of
+    /// the socket must then be able to hold one packet per server.
+    pub fn set_query_mode(&mut self, mode: QueryMode) {
+        self.query_mode = mode;
+    }
+
+    /// Returns the timestamps of the last exchange with the given server for
+    /// which a valid response was received, if any.
+    ///
+    /// The client operates in basic mode, where the server echoes the transmit
+    /// timestamp of the request it replies to. Servers supporting interleaved
+    /// mode may instead refer to the previous request: such responses are
+    /// recognized using this exchange, counted in [`Stats::interleaved`] and
+    /// discarded.
+    ///
+    /// [`Stats::interleaved`]: struct.Stats.html#structfield.interleaved
+    pub fn previous_exchange(&self, addr: IpAddress) -> Option<Exchange> {
+        self.servers.get(addr).and_then(|entry| entry.previous)
+    }
+
+    /// Returns `true` if the server disagreed with the majority of the servers
+    /// in the last poll cycle it replied to.
+    ///
+    /// Falsetickers are only detected in [`QueryMode::Concurrent`], when at
+    /// least three servers reply: a server is a falseticker if its offset
+    /// differs from the median by more than the sum of their maximum errors
+    /// (see [`SyncResult::max_error`]). Falsetickers are excluded from the
+    /// combined result; the other servers are truechimers.
+    ///
+    /// The flag is evaluated anew each time the server replies: it is cleared
+    /// as soon as the server agrees with the majority again, or its reply is
+    /// combined with fewer than two others. It is also cleared by [`reset`]
+    /// and [`clear_falsetickers`], so that a server can be reconsidered right
+    /// away, e.g. once a transient network issue is solved.
+    ///
+    /// [`reset`]: #method.reset
+    /// [`clear_falsetickers`]: #method.clear_falsetickers
+    pub fn is_falseticker(&self, addr: IpAddress) -> bool {
+        self.servers
+            .get(addr)
+            .map_or(false, |entry| entry.falseticker)
+    }
+
+    /// Returns an iterator over the servers currently considered falsetickers.
+    ///
+    /// See [`is_falseticker`](#method.is_falseticker).
+    pub fn falsetickers(&self) -> impl Iterator<Item = IpAddress> + '_ {
+        self.servers
+            .iter()
+            .filter(|entry| entry.falseticker)
+            .map(|entry| entry.addr)
+    }
+
+    /// Clears the falseticker flag of all servers.
+    ///
+    /// See [`is_falseticker`](#method.is_falseticker).
+    pub fn clear_falsetickers(&mut self) {
+        for entry in self.servers.iter_mut() {
+            entry.falseticker = false;
+        }
+    }
+
+    /// Sets which server a request is retried with after a timeout, in
+    /// [`QueryMode::Failover`].
+    ///
+    /// With [`RetryPolicy::NextServer`], the client switches to the next
+    /// usable server as soon as a request goes unanswered, which speeds up
+    /// the recovery when a server is down.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Returns the server requests are currently sent to, if any is usable.
+    pub fn active_server(&self) -> Option<IpAddress> {
+        self.servers.active().map(|entry| entry.addr)
+    }
+
+    /// Excludes a server from selection until [`unblock_server`] is called.
+    ///
+    /// Servers replying with a `DENY` or `RSTR` kiss-o'-death are blocked
+    /// automatically, see [`block_reason`].
+    ///
+    /// Returns `Err(SntpError::UnknownServer)` if `addr` is not a configured server.
+    ///
+    /// [`unblock_server`]: #method.unblock_server
+    /// [`block_reason`]: #method.block_reason
+    pub fn block_server(&mut self, addr: IpAddress) -> Result<()> {
+        let entry = self.servers.get_mut(addr).ok_or(SntpError::UnknownServer)?;
+        entry.blocked = Some(BlockReason::Manual);
+        Ok(())
+    }
+
+    /// Makes a blocked server available for selection again,
+    /// regardless of why it was blocked.
+    ///
+    /// Returns `Err(SntpError::UnknownServer)` if `addr` is not a configured server.
+    pub fn unblock_server(&mut self, addr: IpAddress) -> Result<()> {
+        let entry = self.servers.get_mut(addr).ok_or(SntpError::UnknownServer)?;
+        entry.blocked = None;
+        entry.failures = 0;
+        Ok(())
+    }
+
+    /// Returns why a server is blocked, or `None` if it isn't blocked or configured.
+    pub fn block_reason(&self, addr: IpAddress) -> Option<BlockReason> {
+        self.servers.get(addr).and_then(|entry| entry.blocked)
+    }
+
+    /// Sets the number of consecutive unanswered requests after which a
+    /// synchronized client enters [`SyncState::Holdover`]. Defaults to 3.
+    pub fn set_holdover_threshold(&mut self, threshold: u8) {
+        self.holdover_threshold = threshold;
+    }
+
+    /// Sets the number of valid responses required before the client is synchronized.
+    ///
+    /// Until then, the client stays in [`SyncState::Syncing`] (or in
+    /// [`SyncState::Holdover`] if it was seeded with [`seed_offset`]), to
+    /// avoid declaring it synchronized on the basis of a single, possibly bad
+    /// sample. The results returned during this warm-up are flagged with
+    /// [`SyncResult::is_provisional`]. The warm-up starts over after a
+    /// [`reset`]. The value is capped to the number of retained samples,
+    /// and defaults to 1, i.e. no warm-up.
+    ///
+    /// [`SyncState::Syncing`]: enum.SyncState.html#variant.Syncing
+    /// [`SyncState::Holdover`]: enum.SyncState.html#variant.Holdover
+    /// [`seed_offset`]: #method.seed_offset
+    /// [`SyncResult::is_provisional`]: struct.SyncResult.html#method.is_provisional
+    /// [`reset`]: #method.reset
+    pub fn set_min_samples(&mut self, min_samples: u8) {
+        self.min_samples = min_samples.min(N.min(u8::MAX.into()) as u8);
+    }
+
+    /// Sets whether responses from servers reporting an unsynchronized clock
+    /// (leap indicator set to [`LeapIndicator::AlarmCondition`]) are accepted.
+    ///
+    /// Such responses are discarded by default, since the server has no valid
+    /// time to offer. Enabling this can be useful in lab setups running servers
+    /// without a reference clock, but the time obtained is then arbitrary:
+    /// accepted results are flagged with [`SyncResult::is_unsynchronized`] and
+    /// shouldn't be relied upon for anything that needs the actual time.
+    ///
+    /// [`LeapIndicator::AlarmCondition`]: wire/enum.LeapIndicator.html#variant.AlarmCondition
+    /// [`SyncResult::is_unsynchronized`]: struct.SyncResult.html#method.is_unsynchronized
+    pub fn set_accept_unsynchronized(&mut self, accept: bool) {
+        let alarm = leap_mask(LeapIndicator::AlarmCondition);
+        if accept {
+            self.allowed_leap |= alarm;
+        } else {
+            self.allowed_leap &= !alarm;
+        }
+    }
+
+    /// Sets the leap indicator advertised in requests, for devices that relay time.
+    ///
+    /// By default, requests carry [`LeapIndicator::NoWarning`], as is usual for
+    /// clients. Devices that are themselves a time source can advertise their
+    /// leap second state instead: `leap` is then sent while the client is
+    /// synchronized, and [`LeapIndicator::AlarmCondition`] otherwise, since the
+    /// device has no valid time to offer.
+    ///
+    /// Returns `Err(SntpError::OutOfRange)` for [`LeapIndicator::AlarmCondition`],
+    /// which is only sent when the client isn't synchronized, or for values that
+    /// can't be encoded.
+    ///
+    /// [`LeapIndicator::NoWarning`]: wire/enum.LeapIndicator.html#variant.NoWarning
+    /// [`LeapIndicator::AlarmCondition`]: wire/enum.LeapIndicator.html#variant.AlarmCondition
+    pub fn set_leap_indicator(&mut self, leap: Option<LeapIndicator>) -> Result<()> {
+        match leap {
+            Some(LeapIndicator::AlarmCondition) | Some(LeapIndicator::Unknown(_)) => {
+                Err(SntpError::OutOfRange)
+            }
+            _ => {
+                self.leap_indicator = leap;
+                Ok(())
+            }
+        }
+    }
+
+    /// Sets the leap indicators accepted in responses.
+    ///
+    /// Responses with any other leap indicator are discarded, e.g. to reject
+    /// leap second announcements in deployments that can't handle them.
+    /// Defaults to all of them but [`LeapIndicator::AlarmCondition`], which
+    /// is the same as [`set_accept_unsynchronized`].
+    ///
+    /// [`LeapIndicator::AlarmCondition`]: wire/enum.LeapIndicator.html#variant.AlarmCondition
+    /// [`set_accept_unsynchronized`]: #method.set_accept_unsynchronized
+    pub fn set_allowed_leap(&mut self, allowed: &[LeapIndicator]) {
+        self.allowed_leap = allowed.iter().fold(0, |mask, &li| mask | leap_mask(li));
+    }
+
+    /// Sets the offset above which [`suggested_step`] recommends stepping the
+    /// local clock. Defaults to 128 ms, as in NTP.
+    ///
+    /// [`suggested_step`]: #method.suggested_step
+    pub fn set_step_threshold(&mut self, threshold: Duration) {
+        self.step_threshold = threshold;
+    }
+
+    /// Sets the threshold used instead of the step threshold when detecting
+    /// jumps of the offset (see [`detected_external_step`]) in samples from
+    /// primary servers (stratum 1).
+    ///
+    /// Primary servers are directly connected to a reference clock, e.g. a GPS
+    /// receiver, so a tighter threshold can be applied to them than to deep
+    /// secondary servers. Pass `None` to use the step threshold for all strata,
+    /// which is the default.
+    ///
+    /// [`detected_external_step`]: #method.detected_external_step
+    pub fn set_primary_step_threshold(&mut self, threshold: Option<Duration>) {
+        self.primary_step_threshold = threshold;
+    }
+
+    /// Returns the estimated offset of the server clock relative to the local
+    /// clock at `now`, in microseconds, or `None` if there is no sample.
+    ///
+    /// The offset is extrapolated from the most recent sample, corrected by the
+    /// estimated drift. It is the amount of time to add to `now` to obtain the
+    /// corresponding Unix time.
+    pub fn estimated_offset_micros(&self, now: Instant) -> Option<i64> {
+        self.filter.offset_at(now)
+    }
+
+    /// Returns the magnitude of the step to apply to the local clock at `now`,
+    /// or `None` if slewing it is enough.
+    ///
+    /// This is meaningful when `Instant` is read from the clock being
+    /// disciplined (e.g. the system clock with the `std` feature). A step is
+    /// suggested when the magnitude of the [`estimated_offset_micros`] exceeds
+    /// the step threshold, which also gives the direction of the step.
+    ///
+    /// [`estimated_offset_micros`]: #method.estimated_offset_micros
+    pub fn suggested_step(&self, now: Instant) -> Option<Duration> {
+        let offset = self.estimated_offset_micros(now)?.unsigned_abs();
+        if offset > self.step_threshold.total_millis() * 1_000 {
+            Some(Duration::from_millis(offset / 1_000))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the shortest round-trip delay among the stored samples, in
+    /// microseconds, or `None` if there is no measured sample.
+    ///
+    /// This is the delay of the least congested exchange, and the baseline
+    /// against which the delay of new samples, and the asymmetry they may
+    /// suffer, can be estimated (see [`SyncResult::offset_asymmetry_corrected`]).
+    ///
+    /// [`SyncResult::offset_asymmetry_corrected`]: struct.SyncResult.html#method.offset_asymmetry_corrected
+    pub fn min_delay_micros(&self) -> Option<i64> {
+        if self.seeded {
+            return None;
+        }
+        self.filter.best().map(|sample| sample.delay)
+    }
+
+    /// Returns the estimated drift of the local clock relative to the server,
+    /// in parts per billion.
+    ///
+    /// The drift is estimated from the oldest and the most recent samples, and is
+    /// positive if the local clock runs slower than the server clock. Returns
+    /// `None` until at least two samples have been collected.
+    pub fn drift_ppb(&self) -> Option<i64> {
+        self.filter.drift_ppb()
+    }
+
+    /// Returns the magnitude of the jump of the offset detected with the last
+    /// accepted sample, if any.
+    ///
+    /// A jump is detected when the measured offset differs from the one
+    /// predicted from the previous samples and the estimated drift by more
+    /// than the step threshold (see [`set_step_threshold`], and
+    /// [`set_primary_step_threshold`] for primary servers) plus the maximum
+    /// error of the measurement. This usually means that the local clock was
+    /// stepped by something else, e.g. another time synchronization service
+    /// or a manual change: the previous samples are then discarded, so that
+    /// the drift is estimated anew.
+    ///
+    /// [`set_step_threshold`]: #method.set_step_threshold
+    /// [`set_primary_step_threshold`]: #method.set_primary_step_threshold
+    pub fn detected_external_step(&self) -> Option<Duration> {
+        self.external_step
+            .map(|step| Duration::from_millis(step.unsigned_abs() / 1_000))
+    }
+
+    /// Returns the estimated current time, as an NTP timestamp.
+    ///
+    /// The time is extrapolated from the most recent sample, corrected by the
+    /// estimated drift. This is also available in [`SyncState::Holdover`], in
+    /// which case the uncertainty of the estimate grows with the time elapsed
+    /// since the last valid response (see [`holdover_duration`]).
+    ///
+    /// Returns `None` if the client has never been synchronized.
+    ///
+    /// [`holdover_duration`]: #method.holdover_duration
+    pub fn current_time(&self, now: Instant) -> Option<Timestamp> {
+        match self.state {
+            SyncState::Synced | SyncState::Holdover => self.corrected_time(now),
+            SyncState::Unsynced | SyncState::Syncing => None,
+        }
+    }
+
+    /// Returns the local time corrected with the last offset and the estimated
+    /// drift, as an NTP timestamp, regardless of the synchronization state.
+    ///
+    /// Unlike [`current_time`], this is also available while the client is
+    /// still gathering the samples required by [`set_min_samples`], which
+    /// suits e.g. a clock display that shouldn't wait for the time to be fully
+    /// trusted. It has no side effect, and can be called as often as needed.
+    ///
+    /// Returns `None` if no sample has been accepted, nor seeded, since the
+    /// client was created or reset.
+    ///
+    /// [`current_time`]: #method.current_time
+    /// [`set_min_samples`]: #method.set_min_samples
+    pub fn corrected_time(&self, now: Instant) -> Option<Timestamp> {
+        Some(self.clock_mapping()?.to_wall(now))
+    }
+
+    /// Returns the mapping between the local time and the wall-clock time
+    /// estimated by the client.
+    ///
+    /// The mapping is anchored at the most recent sample, and extrapolated
+    /// with the estimated drift: it changes with each accepted sample. This is
+    /// the mapping [`corrected_time`] uses, and is available under the same
+    /// conditions.
+    ///
+    /// [`corrected_time`]: #method.corrected_time
+    pub fn clock_mapping(&self) -> Option<ClockMapping> {
+        let latest = self.filter.latest()?;
+        Some(ClockMapping {
+            instant: latest.received_at,
+            unix_micros: micros(latest.received_at) + latest.offset,
+            drift_ppb: self.filter.drift_ppb().unwrap_or(0),
+        })
+    }
+
+    /// Seeds the client with an estimate of the offset, e.g. from a battery-backed RTC.
+    ///
+    /// `offset_micros` is the amount of time to add to a local `Instant` to
+    /// obtain the corresponding Unix time, as estimated at `as_of`, in
+    /// microseconds: like [`SyncResult::offset_micros`], it is negative if the
+    /// local clock is ahead. The client enters
+    /// [`SyncState::Holdover`], so that [`current_time`] is available right away
+    /// and the estimate is used for the transmit timestamps of the requests.
+    ///
+    /// The seed is only an estimate: it is discarded, along with any previous
+    /// sample, when the first valid response is received.
+    ///
+    /// [`current_time`]: #method.current_time
+    /// [`SyncResult::offset_micros`]: struct.SyncResult.html#method.offset_micros
+    pub fn seed_offset(&mut self, offset_micros: i64, as_of: Instant) {
+        self.filter.clear();
+        self.filter.push(Sample {
+            offset: offset_micros,
+            delay: 0,
+            received_at: as_of,
+        });
+        self.seeded = true;
+        self.state = SyncState::Holdover;
+    }
+
+    /// Returns how long the client has been relying on extrapolated time, i.e.
+    /// the time since the last valid response, if in [`SyncState::Holdover`].
+    ///
+    /// Applications can use this to cap how long they trust [`current_time`].
+    ///
+    /// [`current_time`]: #method.current_time
+    pub fn holdover_duration(&self, now: Instant) -> Option<Duration> {
+        match self.state {
+            SyncState::Holdover => self.filter.latest().map(|s| now - s.received_at),
+            _ => None,
+        }
+    }
+
+    /// Sets which offset is reported for each accepted response.
+    ///
+    /// All the samples are retained regardless of this setting, which can
+    /// thus be changed at any time: it only affects the offset and delay of
+    /// the results returned by [`poll`] and passed to the sync hook. The other
+    /// fields, including the maximum error, always describe the last response.
+    ///
+    /// [`poll`]: #method.poll
+    pub fn set_sample_selection(&mut self, selection: SampleSelection) {
+        self.sample_selection = selection;
+    }
+
+    /// Sets the maximum number of received packets processed by a single call
+    /// to [`poll`]. Defaults to 4.
+    ///
+    /// This bounds the time spent in [`poll`], e.g. on cooperative schedulers:
+    /// packets beyond the budget are left in the socket buffer, and processed
+    /// by the next calls. Processing also stops at the first valid response.
+    /// A budget of 0 is treated as 1.
+    ///
+    /// [`poll`]: #method.poll
+    pub fn set_packet_budget(&mut self, budget: usize) {
+        self.packet_budget = budget.max(1);
+    }
+
+    /// Sets the maximum size of the received packets, in bytes. Defaults to
+    /// [`MAX_PACKET_LEN`].
+    ///
+    /// Larger packets, e.g. with many extension fields, are rejected with
+    /// `Err(SntpError::PacketTooLarge)` before being parsed. A maximum below
+    /// [`MIN_BUFFER_LEN`] is treated as [`MIN_BUFFER_LEN`].
+    ///
+    /// [`MAX_PACKET_LEN`]: constant.MAX_PACKET_LEN.html
+    /// [`MIN_BUFFER_LEN`]: constant.MIN_BUFFER_LEN.html
+    pub fn set_max_packet_len(&mut self, len: usize) {
+        self.max_packet_len = len.max(MIN_BUFFER_LEN);
+    }
+
+    /// Sets the maximum difference between the transmit time of a response
+    /// and the current time estimated by the client.
+    ///
+    /// When set, responses further away from the estimate are discarded as
+    /// implausible, e.g. replayed packets or badly misconfigured servers. This
+    /// only applies once the client has an estimate of the time, i.e. after
+    /// the first valid response or after seeding the offset with
+    /// [`seed_offset`]. By default, no limit is enforced.
+    ///
+    /// Note that a genuine step of the local clock larger than the window
+    /// makes all responses look implausible: see [`reset`].
+    ///
+    /// [`seed_offset`]: #method.seed_offset
+    /// [`reset`]: #method.reset
+    pub fn set_acceptance_window(&mut self, window: Option<Duration>) {
+        self.acceptance_window = window;
+    }
 
+    /// Sets the maximum time the server may spend processing a request.
+    ///
+    /// When set, responses whose processing delay (see
+    /// [`SyncResult::processing_delay_micros`]) is negative or larger than `max`
+    /// are discarded, and counted in [`Stats::processing_delay_rejected`]. By
+    /// default, no limit is enforced.
+    ///
+    /// [`Stats::processing_delay_rejected`]: struct.Stats.html#structfield.processing_delay_rejected
+    pub fn set_max_processing_delay(&mut self, max: Option<Duration>) {
+        self.max_processing_delay = max;
+    }
+
+    /// Sets the maximum time since the server last synchronized to its own
+    /// time source.
+    ///
+    /// When set, responses whose server sync age (see
+    /// [`SyncResult::server_sync_age`]) is larger than `max`, or unknown
+    /// because the server never synchronized, are discarded and counted in
+    /// [`Stats`]. By default, no limit is enforced.
+    ///
+    /// [`SyncResult::server_sync_age`]: struct.SyncResult.html#method.server_sync_age
+    /// [`Stats`]: struct.Stats.html
+    pub fn set_max_server_sync_age(&mut self, max: Option<Duration>) {
+        self.max_server_sync_age = max;
+    }
+
+    /// Sets whether responses with out-of-range or inconsistent fields are discarded.
+    ///
+    /// With strict validation, a response is discarded, and counted in
+    /// [`Stats`], if:
+    ///
+    /// - its precision is outside of -32..=10 (about 0.2 ns to 17 minutes);
+    /// - its poll interval is outside of 3..=17 (8 s to 36 hours), which
+    ///   excludes servers that leave the field zeroed;
+    /// - it comes from a primary server (stratum 1) and its reference
+    ///   identifier isn't a printable ASCII code, e.g. an upstream address.
+    ///
+    /// Disabled by default.
+    ///
+    /// [`Stats`]: struct.Stats.html
+    pub fn set_strict_validation(&mut self, strict: bool) {
+        self.strict_validation = strict;
+    }
+
+    /// Sets whether responses from misconfigured servers are discarded.
+    ///
+    /// When enabled, a response is discarded, and counted in [`Stats`], if:
+    ///
+    /// - it comes from a secondary server (stratum 2 or more) whose reference
+    ///   identifier is its own address, i.e. it is synchronized to itself;
+    /// - its reference timestamp is later than its transmit timestamp, i.e. the
+    ///   server claims to have been synchronized in the future.
+    ///
+    /// Enabled by default.
+    ///
+    /// [`Stats`]: struct.Stats.html
+    pub fn set_loop_detection(&mut self, enabled: bool) {
+        self.loop_detection = enabled;
+    }
+
+    /// Sets whether responses without a valid MAC are discarded.
+    ///
+    /// When required, every accepted sample has been authenticated (see
+    /// [`SyncResult::is_authenticated`]), and other responses are discarded
+    /// and counted in [`Stats`]. Since symmetric key authentication isn't
+    /// supported yet, no response can be authenticated: requiring it stops
+    /// the synchronization altogether, rather than falling back to
+    /// unauthenticated time.
+    ///
+    /// Disabled by default.
+    ///
+    /// [`SyncResult::is_authenticated`]: struct.SyncResult.html#method.is_authenticated
+    /// [`Stats`]: struct.Stats.html
+    pub fn set_require_auth(&mut self, required: bool) {
+        self.require_auth = required;
+    }
+
+    /// Restricts the addresses packets are accepted from.
+    ///
+    /// Packets from any other address are dropped before being parsed, and
+    /// counted in [`Stats::untrusted_source`], whatever their mode: unlike the
+    /// configured servers, which may change with [`add_server`], this allowlist
+    /// is fixed by the application, e.g. to the servers of a trusted network.
+    /// Pass an empty slice to accept packets from any address, the default.
+    ///
+    /// Returns `Err(SntpError::TooManyServers)` if more than [`MAX_SERVERS`]
+    /// addresses are given, leaving the allowlist unchanged.
+    ///
+    /// [`Stats::untrusted_source`]: struct.Stats.html#structfield.untrusted_source
+    /// [`add_server`]: #method.add_server
+    /// [`MAX_SERVERS`]: constant.MAX_SERVERS.html
+    pub fn set_trusted_sources(&mut self, sources: &[IpAddress]) -> Result<()> {
+        if sources.len() > MAX_SERVERS {
+            return Err(SntpError::TooManyServers);
+        }
+        self.trusted_sources = [None; MAX_SERVERS];
+        for (slot, &addr) in self.trusted_sources.iter_mut().zip(sources) {
+            *slot = Some(addr);
+        }
+        Ok(())
+    }
+
+    /// Returns whether packets from `addr` are accepted, see [`set_trusted_sources`].
+    ///
+    /// [`set_trusted_sources`]: #method.set_trusted_sources
+    fn is_trusted(&self, addr: IpAddress) -> bool {
+        self.trusted_sources.iter().all(Option::is_none)
+            || self.trusted_sources.contains(&Some(addr))
+    }
+
+    /// Sets whether requests are tagged with a sequence number.
+    ///
+    /// When enabled, the 8 least significant bits of the fraction of the
+    /// transmit timestamp of each request carry a sequence number, incremented
+    /// with each request and wrapping around, which helps matching requests
+    /// and responses in packet captures. These bits weigh less than 60 ns in
+    /// total, well below the microsecond resolution of the timestamps.
+    ///
+    /// Responses must then echo the sequence number exactly: they are otherwise
+    /// discarded and counted in [`Stats::wrong_origin`]. Disabled by default.
+    ///
+    /// [`Stats::wrong_origin`]: struct.Stats.html#structfield.wrong_origin
+    pub fn set_sequence_tagging(&mut self, enabled: bool) {
+        self.sequence_tagging = enabled;
+    }
+
+    /// Returns the sequence number of the last request, if sequence tagging
+    /// is enabled (see [`set_sequence_tagging`]).
+    ///
+    /// [`set_sequence_tagging`]: #method.set_sequence_tagging
+    pub fn last_sequence(&self) -> Option<u8> {
+        if self.sequence_tagging {
+            Some(self.sequence)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the current synchronization state of the client.
+    ///
+    /// The client starts in [`SyncState::Unsynced`], moves to [`SyncState::Syncing`]
+    /// when the first request is sent and to [`SyncState::Synced`] once a valid
+    /// response is received. If a synchronized client sees several consecutive
+    /// requests go unanswered, it enters [`SyncState::Holdover`] until the next
+    /// valid response.
+    pub fn state(&self) -> SyncState {
+        self.state
+    }
+
+    /// Returns the time elapsed since the last successful synchronization,
+    /// or `None` if the client has never been synchronized.
+    ///
+    /// Unlike [`state`], this keeps growing while the server doesn't answer,
+    /// so that e.g. a watchdog can take action when the time hasn't been
+    /// synchronized for too long. Seeding the offset with [`seed_offset`]
+    /// doesn't count as a synchronization.
+    ///
+    /// [`state`]: #method.state
+    /// [`seed_offset`]: #method.seed_offset
+    pub fn time_since_last_sync(&self, now: Instant) -> Option<Duration> {
+        self.last_sync.map(|last| {
+            if now > last {
+                now - last
+            } else {
+                Duration::from_millis(0)
+            }
+        })
+    }
+
+    /// Returns the current synchronization distance, i.e. an upper bound on
+    /// the error of the time estimated by the client, rounded up to the millisecond.
+    ///
+    /// This is the sum of:
+    ///
+    /// - the maximum error of the last accepted sample (see
+    ///   [`SyncResult::max_error`]), which accounts for the root delay and root
+    ///   dispersion of the server, the round trip and the clock precisions;
+    /// - the dispersion accumulated since then, assuming the local clock
+    ///   drifts by at most 15 ppm;
+    /// - the jitter of the retained samples, i.e. the root mean square of the
+    ///   differences between their offsets and the one of the sample with the
+    ///   shortest round trip.
+    ///
+    /// Returns `None` if the client has never been synchronized.
+    ///
+    /// [`SyncResult::max_error`]: struct.SyncResult.html#method.max_error
+    pub fn sync_distance(&self, now: Instant) -> Option<Duration> {
+        let distance = self.distance_micros(now)?;
+        Some(Duration::from_millis((distance as u64 + 999) / 1_000))
+    }
+
+    /// Returns the synchronization distance, in microseconds.
+    fn distance_micros(&self, now: Instant) -> Option<i64> {
+        let last = self.last_sync?;
+        let elapsed = (now.total_millis() - last.total_millis()).max(0) * 1_000;
+        Some(
+            self.last_max_error.max(0) + elapsed * MAX_DRIFT_PPM / 1_000_000 + self.filter.jitter(),
+        )
+    }
+
+    /// Returns the root delay to advertise when serving the time obtained by
+    /// the client, rounded up to the millisecond.
+    ///
+    /// This is the round-trip delay to the primary reference through the
+    /// server of the last accepted sample (see [`SyncResult::root_delay_micros`]).
+    /// Returns `None` if the client has never been synchronized.
+    ///
+    /// The requests of the client itself always carry a zero root delay and
+    /// root dispersion, as a client isn't a time source. A device that also
+    /// acts as a [`Server`] relays these values with [`Server::relay`].
+    ///
+    /// [`SyncResult::root_delay_micros`]: struct.SyncResult.html#method.root_delay_micros
+    /// [`Server`]: struct.Server.html
+    /// [`Server::relay`]: struct.Server.html#method.relay
+    pub fn root_delay(&self) -> Option<Duration> {
+        self.last_sync?;
+        Some(Duration::from_millis(
+            (self.last_root_delay.max(0) as u64 + 999) / 1_000,
+        ))
+    }
+
+    /// Returns the root dispersion to advertise when serving the time obtained
+    /// by the client, rounded up to the millisecond.
+    ///
+    /// This is the part of the [`sync_distance`] that isn't due to the root
+    /// delay, so that the synchronization distance computed by the clients of
+    /// the device (root dispersion plus half the root delay) matches the one of
+    /// the device itself. It grows with the time elapsed since the last sample.
+    /// Returns `None` if the client has never been synchronized.
+    ///
+    /// See also [`root_delay`].
+    ///
+    /// [`sync_distance`]: #method.sync_distance
+    /// [`root_delay`]: #method.root_delay
+    pub fn root_dispersion(&self, now: Instant) -> Option<Duration> {
+        let dispersion = self.distance_micros(now)? - self.last_root_delay.max(0) / 2;
+        Some(Duration::from_millis(
+            (dispersion.max(0) as u64 + 999) / 1_000,
+        ))
+    }
+
+    /// Returns the stratum of the server of the last accepted sample, if any.
+    pub(crate) fn last_stratum(&self) -> Option<u8> {
+        self.last_sync.map(|_| self.last_stratum)
+    }
+
+    /// Returns the address of the server of the last accepted sample, if any.
+    pub(crate) fn last_server(&self) -> Option<IpAddress> {
+        self.last_sync.map(|_| self.last_server)
+    }
+
+    /// Returns the estimated current time along with its uncertainty, i.e.
+    /// the time is within the returned duration of the timestamp.
+    ///
+    /// The time is the one returned by [`current_time`], and the uncertainty
+    /// the [`sync_distance`], which grows with the time elapsed since the last
+    /// sample to account for the drift of the local clock.
+    ///
+    /// Returns `None` if the client has never been synchronized. A seeded
+    /// offset (see [`seed_offset`]) has no known uncertainty, and isn't reported.
+    ///
+    /// [`current_time`]: #method.current_time
+    /// [`sync_distance`]: #method.sync_distance
+    /// [`seed_offset`]: #method.seed_offset
+    pub fn time_with_uncertainty(&self, now: Instant) -> Option<(Timestamp, Duration)> {
+        if self.seeded {
+            return None;
+        }
+        Some((self.current_time(now)?, self.sync_distance(now)?))
+    }
+
+    /// Returns `true` if the estimated time is good enough for a purpose
+    /// requiring at most `max_distance` of error, e.g. checking the validity
+    /// period of a certificate.
+    ///
+    /// This requires the client to be synchronized, or in holdover, with at
+    /// least the number of samples set with [`set_min_samples`], and the
+    /// [`sync_distance`] not to exceed `max_distance`. As the distance grows
+    /// with the time elapsed since the last sample, a trustworthy time
+    /// eventually stops being so if the server doesn't answer.
+    ///
+    /// [`set_min_samples`]: #method.set_min_samples
+    /// [`sync_distance`]: #method.sync_distance
+    pub fn is_time_trustworthy(&self, now: Instant, max_distance: Duration) -> bool {
+        let synchronized = matches!(self.state, SyncState::Synced | SyncState::Holdover);
+        if !synchronized || self.accepted < self.min_samples {
+            return false;
+        }
+        self.sync_distance(now)
+            .map_or(false, |distance| distance <= max_distance)
+    }
+
+    /// Sets the minimum poll interval, as log2 seconds.
+    ///
+    /// Requests start at this interval and back off exponentially while the
+    /// server doesn't answer. Defaults to 6 (64 seconds).
+    ///
+    /// Returns `Err(SntpError::InvalidPollInterval)` if `minpoll` is below 4
+    /// (16 seconds, the RFC 4330 floor) or above the current maximum poll interval.
+    pub fn set_minpoll(&mut self, minpoll: u8) -> Result<()> {
+        if minpoll < MIN_POLL_EXPONENT || minpoll > self.maxpoll {
+            return Err(SntpError::InvalidPollInterval);
+        }
+        self.minpoll = minpoll;
+        self.curr_interval = self.clamp_interval(self.curr_interval);
+        Ok(())
+    }
+
+    /// Sets the maximum poll interval, as log2 seconds.
+    ///
+    /// This is the interval used after a successful synchronization, and the
+    /// upper bound for the backoff. Defaults to 16 (about 18 hours).
+    ///
+    /// Returns `Err(SntpError::InvalidPollInterval)` if `maxpoll` is above 17
+    /// (about 36 hours, the RFC 4330 ceiling) or below the current minimum poll interval.
+    pub fn set_maxpoll(&mut self, maxpoll: u8) -> Result<()> {
+        if maxpoll > MAX_POLL_EXPONENT || maxpoll < self.minpoll {
+            return Err(SntpError::InvalidPollInterval);
+        }
+        self.maxpoll = maxpoll;
+        self.curr_interval = self.clamp_interval(self.curr_interval);
         Ok(())
     }
+
+    /// Sets a fixed poll interval of `2^exponent` seconds, as `minpoll` and
+    /// `maxpoll` would be set to the same value in `ntp.conf`.
+    ///
+    /// Requests are then sent at this interval, whether the server answers or
+    /// not, unless it advertises a longer poll interval.
+    ///
+    /// Returns `Err(SntpError::InvalidPollInterval)` if `exponent` is outside
+    /// of 3..=17, so that values can be copied from `ntp.conf`. Note that 3
+    /// (8 seconds) is below the 15 seconds floor of RFC 4330, and is only
+    /// suitable for servers under the same administration, e.g. on a LAN.
+    pub fn set_poll_exponent(&mut self, exponent: u8) -> Result<()> {
+        if !(MIN_FIXED_POLL_EXPONENT..=MAX_POLL_EXPONENT).contains(&exponent) {
+            return Err(SntpError::InvalidPollInterval);
+        }
+        self.minpoll = exponent;
+        self.maxpoll = exponent;
+        self.curr_interval = self.clamp_interval(self.curr_interval);
+        Ok(())
+    }
+
+    /// Sets how requests are scheduled.
+    ///
+    /// [`PollSchedule::FastThenLazy`] suits devices that mostly need the
+    /// correct time once, and occasional refreshes: it is simpler to reason
+    /// about than the default backoff.
+    ///
+    /// Returns `Err(SntpError::InvalidPollInterval)` if the fast interval is
+    /// longer than the lazy one, or if either of them is outside the minimum
+    /// and maximum poll intervals.
+    pub fn set_poll_schedule(&mut self, schedule: PollSchedule) -> Result<()> {
+        if let PollSchedule::FastThenLazy { fast, lazy } = schedule {
+            if fast < self.minpoll || fast > lazy || lazy > self.maxpoll {
+                return Err(SntpError::InvalidPollInterval);
+            }
+        }
+        self.schedule = schedule;
+        Ok(())
+    }
+
+    /// Clamps an interval between the effective minimum and maximum poll intervals.
+    ///
+    /// The minimum is raised to the poll interval advertised by the server,
+    /// so that the client never polls faster than the server asks for.
+    fn clamp_interval(&self, interval: Duration) -> Duration {
+        interval
+            .max(self.min_spacing())
+            .min(poll_interval(self.maxpoll))
+    }
+
+    /// Returns the minimum time between two consecutive requests.
+    ///
+    /// This is the poll interval advertised by the server, if any,
+    /// or the minimum poll interval otherwise.
+    fn min_spacing(&self) -> Duration {
+        let min = match self.server_poll {
+            Some(poll) => self.minpoll.max(poll).min(self.maxpoll),
+            None => self.minpoll,
+        };
+        poll_interval(min)
+    }
+
+    /// Returns when the next request will be sent, taking rate limiting into account.
+    fn next_request_at(&self) -> Instant {
+        let next = match self.last_request {
+            Some(last) => self.next_request.max(last + self.min_spacing()),
+            None => self.next_request,
+        };
+        let next = match self.rate_limited_until {
+            Some(until) => next.max(until),
+            None => next,
+        };
+        match self.network_retry_at {
+            Some(retry_at) => next.max(retry_at),
+            None => next,
+        }
+    }
+
+    /// Returns the backoff applied after consecutive network errors, if any.
+    ///
+    /// When sending or receiving fails at the network level (e.g. the server
+    /// is unaddressable), requests are delayed by an exponentially increasing,
+    /// randomly jittered amount of time, from 1 s up to the minimum poll interval.
+    /// This is independent of the poll interval negotiated with the server, and
+    /// is cleared by the first successful request.
+    pub fn network_backoff(&self) -> Option<Duration> {
+        self.network_backoff
+    }
+
+    /// Records a network error and delays the next request accordingly.
+    fn on_network_error(&mut self, now: Instant) {
+        self.network_errors = self.network_errors.saturating_add(1);
+
+        let exp = u32::from(self.network_errors - 1).min(MAX_POLL_EXPONENT.into());
+        let base = (NETWORK_BACKOFF_MIN * 2u32.pow(exp)).min(poll_interval(self.minpoll));
+
+        // Pick a delay in [base / 2, base] to avoid synchronized retries
+        let half = base.total_millis() / 2;
+        let backoff = Duration::from_millis(half + u64::from(self.next_random()) % (half + 1));
+
+        net_debug!("SNTP network error, retrying in {}", backoff);
+        self.network_backoff = Some(backoff);
+        self.network_retry_at = Some(now + backoff);
+    }
+
+    /// Returns the next value of the xorshift32 pseudo-random generator.
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+        x
+    }
+
+    /// Requests a synchronization as soon as possible.
+    ///
+    /// To avoid exceeding the server rate limits, requests are never sent more
+    /// often than the poll interval advertised by the server, or the minimum
+    /// poll interval if the server didn't advertise any. If the last request
+    /// was sent too recently, the new request is deferred until it is allowed.
+    ///
+    /// Likewise, after a `RATE` kiss-o'-death the client backs off for the
+    /// maximum poll interval, and the request is deferred until the backoff
+    /// expires. Use [`force_sync_override`] to send a request regardless.
+    ///
+    /// Servers that denied access with a `DENY` or `RSTR` kiss-o'-death are
+    /// blocked, and are never queried again until [`unblock_server`] is called,
+    /// whether the synchronization is forced or not.
+    ///
+    /// [`force_sync_override`]: #method.force_sync_override
+    /// [`unblock_server`]: #method.unblock_server
+    pub fn force_sync(&mut self, now: Instant) {
+        self.next_request = now;
+    }
+
+    /// Requests a synchronization right away, ignoring the rate limits.
+    ///
+    /// Unlike [`force_sync`], this disregards both the poll interval advertised
+    /// by the server and the backoff requested by a `RATE` kiss-o'-death: it
+    /// should be used sparingly, as servers may block clients that don't honor
+    /// their rate limits. Blocked servers are still not queried.
+    ///
+    /// [`force_sync`]: #method.force_sync
+    pub fn force_sync_override(&mut self, now: Instant) {
+        self.next_request = now;
+        self.last_request = None;
+        self.rate_limited_until = None;
+    }
+
+    /// Returns when the next request will be sent.
+    ///
+    /// This is the time scheduled by the poll interval or by
+    /// [`set_next_request`], deferred by the rate limits and the network
+    /// backoff if needed.
+    ///
+    /// [`set_next_request`]: #method.set_next_request
+    pub fn next_request(&self) -> Instant {
+        self.next_request_at()
+    }
+
+    /// Schedules the next request at the given time, replacing the time
+    /// computed from the poll interval.
+    ///
+    /// The same protections as with [`force_sync`] apply: the request is
+    /// deferred if it would be sent sooner than the minimum spacing after the
+    /// previous one, during a `RATE` kiss-o'-death backoff, or during a
+    /// network backoff. Check [`next_request`] for the actual time.
+    ///
+    /// [`force_sync`]: #method.force_sync
+    /// [`next_request`]: #method.next_request
+    pub fn set_next_request(&mut self, at: Instant) {
+        self.next_request = at;
+    }
+
+    /// Resets the client to its unsynchronized state, and requests a
+    /// synchronization as soon as possible.
+    ///
+    /// This discards everything learned since the client was created, e.g.
+    /// after a network change or a resume from suspend:
+    ///
+    /// - the samples, and thus the estimated offset and drift, as well as the
+    ///   last response received and the last detected external step;
+    /// - the synchronization state, which goes back to [`SyncState::Unsynced`];
+    /// - the current poll interval and the one advertised by the server;
+    /// - the outstanding request, the rate limiting and the network backoff;
+    /// - the per-server state: failures, blocks, falsetickers and previous
+    ///   exchanges. The first configured server becomes the active one again.
+    ///
+    /// The configuration (servers, poll intervals, policies, hooks and clock),
+    /// the socket and its binding, and the [`stats`] are kept.
+    ///
+    /// [`SyncState::Unsynced`]: enum.SyncState.html#variant.Unsynced
+    /// [`stats`]: #method.stats
+    pub fn reset(&mut self, now: Instant) {
+        net_debug!("SNTP reset");
+        self.servers.reset();
+        self.collect_until = None;
+        self.next_request = now;
+        self.last_sync = None;
+        self.curr_interval = poll_interval(self.minpoll);
+        self.server_poll = None;
+        self.state = SyncState::Unsynced;
+        self.outstanding = false;
+        self.unanswered = 0;
+        self.accepted = 0;
+        self.network_errors = 0;
+        self.network_backoff = None;
+        self.network_retry_at = None;
+        self.last_request = None;
+        self.rate_limited_until = None;
+        self.filter.clear();
+        self.last_repr = None;
+        self.last_raw_request = None;
+        self.seeded = false;
+        self.external_step = None;
+    }
+
+    /// Sets whether the client binds its socket when it isn't open.
+    ///
+    /// By default, [`poll`] binds the socket to port 123 on any local address.
+    /// Applications that bind the socket themselves, e.g. to choose the local
+    /// endpoint, can disable this: [`poll`] then returns
+    /// `Err(SntpError::NotBound)` as long as the socket isn't open.
+    ///
+    /// [`poll`]: #method.poll
+    pub fn set_auto_bind(&mut self, auto_bind: bool) {
+        self.auto_bind = auto_bind;
+    }
+
+    /// Sets an additional UDP socket on which responses are received.
+    ///
+    /// Requests are always sent from the client socket, but [`poll`] then
+    /// processes the packets received on both sockets, e.g. when replies are
+    /// expected on port 123 while requests are sent from an ephemeral port.
+    /// The receive socket is never bound automatically: [`poll`] returns
+    /// `Err(SntpError::NotBound)` as long as it isn't open.
+    /// Pass `None` to only use the client socket again, which is the default.
+    ///
+    /// Returns `Err(SntpError::InvalidSocket)` if `rx_handle` does not refer
+    /// to a UDP socket of `sockets`, or the same error as [`with_socket`] if
+    /// its buffers can't hold SNTP packets.
+    ///
+    /// [`poll`]: #method.poll
+    /// [`with_socket`]: #method.with_socket
+    pub fn set_receive_socket(
+        &mut self,
+        sockets: &SocketSet,
+        rx_handle: Option<SocketHandle>,
+    ) -> Result<()> {
+        if let Some(handle) = rx_handle {
+            check_socket(sockets, handle)?;
+        }
+        self.rx_handle = rx_handle;
+        Ok(())
+    }
+
+    /// Notifies the client of the state of the network link.
+    ///
+    /// While the link is down no request is sent, but the schedule is kept.
+    /// A request in flight when the link goes down is considered lost and is
+    /// not counted as unanswered. When the link comes back up, a synchronization
+    /// is triggered as with [`force_sync`].
+    ///
+    /// The link is assumed to be up by default.
+    ///
+    /// [`force_sync`]: #method.force_sync
+    pub fn set_link_up(&mut self, up: bool, now: Instant) {
+        if up == self.link_up {
+            return;
+        }
+        self.link_up = up;
+
+        if up {
+            net_debug!("SNTP link up");
+            self.force_sync(now);
+        } else {
+            net_debug!("SNTP link down");
+            self.outstanding = false;
+            self.collect_until = None;
+            self.servers.clear_requests();
+        }
+    }
+
+    /// Cancels the outstanding request, if any.
+    ///
+    /// Responses to the cancelled request arriving later are dropped and
+    /// counted as coming from an unexpected source. The cancelled request is
+    /// not accounted as unanswered, and the next one is still sent according to
+    /// the current poll interval.
+    pub fn cancel_request(&mut self) {
+        if self.outstanding {
+            net_debug!("SNTP request cancelled");
+        }
+        self.outstanding = false;
+        self.collect_until = None;
+        self.servers.clear_requests();
+    }
+
+    /// Prepares the client socket for a change of the local address, e.g. a
+    /// new DHCP lease. This should be called on link or address change events.
+    ///
+    /// The outstanding request is cancelled (see [`cancel_request`]), as its
+    /// response may never arrive, and the packets waiting in the receive
+    /// buffer are discarded.
+    ///
+    /// UDP sockets can't be closed with `smoltcp` 0.6: a socket bound to the
+    /// unspecified address, as done by [`poll`] when binding automatically,
+    /// keeps working with the new address. A socket bound to a specific
+    /// address can't be rebound, and `Err(SntpError::Network(Error::Illegal))`
+    /// is returned: the application must create a new client with a new
+    /// socket instead. `Err(SntpError::InvalidSocket)` is returned for clients
+    /// without a socket.
+    ///
+    /// [`cancel_request`]: #method.cancel_request
+    /// [`poll`]: #method.poll
+    pub fn rebind(&mut self, sockets: &mut SocketSet) -> Result<()> {
+        let udp_handle = self.udp_handle.ok_or(SntpError::InvalidSocket)?;
+        let mut socket = sockets.get::<UdpSocket>(udp_handle);
+
+        self.cancel_request();
+        while socket.recv().is_ok() {}
+
+        if socket.is_open() && !socket.endpoint().addr.is_unspecified() {
+            net_debug!("SNTP socket bound to {}, can't rebind", socket.endpoint());
+            return Err(Error::Illegal.into());
+        }
+        Ok(())
+    }
+
+    /// Sets the precision of the local clock advertised in requests, as log2 seconds.
+    ///
+    /// The precision is the base-2 logarithm of the clock resolution, rounded up:
+    /// a clock ticking every millisecond has a precision of -10 (2^-10 s is about
+    /// 0.98 ms), one ticking every microsecond a precision of -20.
+    /// Defaults to -10, matching the resolution of `Instant`.
+    pub fn set_precision(&mut self, precision: i8) {
+        self.precision = precision;
+    }
+
+    /// Sets the actual resolution of the local clock.
+    ///
+    /// When set, the offsets and delays are rounded to the nearest multiple of
+    /// `resolution`, so that they don't pretend to be more precise than what
+    /// the clock can measure, and the estimated maximum error accounts for at
+    /// least this resolution. By default, no rounding is performed. A zero
+    /// resolution disables the rounding.
+    pub fn set_clock_resolution(&mut self, resolution: Duration) {
+        let resolution = resolution.total_millis() as i64 * 1_000;
+        self.resolution = Some(resolution).filter(|&r| r > 0);
+    }
+
+    /// Sets the Differentiated Services Code Point (DSCP) for outgoing requests.
+    ///
+    /// Only the six least significant bits of `dscp` are meaningful
+    /// (e.g. 48 for CS6). Pass `None` to leave the field unset, which is the default.
+    ///
+    /// Note that `smoltcp` 0.6 does not expose the DSCP/traffic class field of
+    /// UDP sockets: with this version the value is recorded, but outgoing packets
+    /// are still sent with the default DSCP of the interface.
+    pub fn set_dscp(&mut self, dscp: Option<u8>) {
+        self.dscp = dscp.map(|d| d & 0x3f);
+    }
+
+    /// Returns the DSCP value requested for outgoing requests, if any.
+    pub fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+
+    /// Returns the poll interval currently in effect.
+    ///
+    /// Once a valid response has been accepted, and as long as the following
+    /// requests are answered, this is the maximum poll interval, after which
+    /// the next request is sent. Otherwise, this is the time the client waits
+    /// for a response before sending another request: it starts at the minimum
+    /// poll interval and doubles with each unanswered request, up to the
+    /// maximum poll interval. It is never shorter than the poll interval
+    /// advertised by the server.
+    ///
+    /// With [`PollSchedule::FastThenLazy`], this is the fast interval until
+    /// the first valid response, and the lazy one afterwards.
+    ///
+    /// [`PollSchedule::FastThenLazy`]: enum.PollSchedule.html#variant.FastThenLazy
+    pub fn effective_poll_interval(&self) -> Duration {
+        match self.schedule {
+            PollSchedule::Backoff if self.last_sync.is_some() && self.unanswered == 0 => {
+                poll_interval(self.maxpoll)
+            }
+            _ => self.retry_interval(),
+        }
+    }
+
+    /// Returns the time to wait for a response before sending another request.
+    fn retry_interval(&self) -> Duration {
+        match self.schedule {
+            PollSchedule::Backoff => self.clamp_interval(self.curr_interval),
+            PollSchedule::FastThenLazy { fast, .. } if self.last_sync.is_none() => {
+                self.clamp_interval(poll_interval(fast))
+            }
+            PollSchedule::FastThenLazy { lazy, .. } => self.clamp_interval(poll_interval(lazy)),
+        }
+    }
+
+    /// Returns the duration until the next packet request.
+    ///
+    /// Useful for suspending execution after polling.
+    pub fn next_poll(&self, now: Instant) -> Duration {
+        let next_request = match self.collect_until {
+            Some(until) => self.next_request_at().min(until),
+            None => self.next_request_at(),
+        };
+        if next_request > now {
+            next_request - now
+        } else {
+            Duration::from_millis(0)
+        }
+    }
+
+    /// Processes incoming packets, and sends SNTP requests when timeouts expire.
+    ///
+    /// If a valid response is received, the outcome of the synchronization is returned.
+    ///
+    /// Returns `Err(SntpError::BufferTooSmall)` if the socket buffers are smaller
+    /// than [`MIN_BUFFER_LEN`]. If the transmit buffer is merely full, the request
+    /// is deferred to the next poll instead.
+    ///
+    /// Returns `Err(SntpError::NoServersAvailable)` whenever a request is due
+    /// but all servers are blocked, until one of them is unblocked.
+    ///
+    /// Received packets that [`process_response`] would reject with an error,
+    /// i.e. of an unexpected mode or version, counted in [`Stats::invalid_header`],
+    /// or too large, counted in [`Stats::oversized`], are skipped instead: they
+    /// are only reported to the error hook.
+    ///
+    /// [`MIN_BUFFER_LEN`]: constant.MIN_BUFFER_LEN.html
+    /// [`process_response`]: #method.process_response
+    /// [`Stats::invalid_header`]: struct.Stats.html#structfield.invalid_header
+    /// [`Stats::oversized`]: struct.Stats.html#structfield.oversized
+    pub fn poll(&mut self, sockets: &mut SocketSet, now: Instant) -> Result<Option<SyncResult>> {
+        let result = self.dispatch(sockets, now);
+
+        match result {
+            Ok(Some(ref sync)) => self.notify_sync(sync),
+            Err(ref err) => self.notify_error(err),
+            Ok(None) => (),
+        }
+
+        result
+    }
+
+    /// Same as [`poll`], also returning when the client should be polled next.
+    ///
+    /// The returned instant accounts for the state changes made by the poll,
+    /// i.e. it is `now` plus the value [`next_poll`] would return afterwards.
+    ///
+    /// [`poll`]: #method.poll
+    /// [`next_poll`]: #method.next_poll
+    pub fn poll_with_deadline(
+        &mut self,
+        sockets: &mut SocketSet,
+        now: Instant,
+    ) -> (Result<Option<SyncResult>>, Instant) {
+        let result = self.poll(sockets, now);
+        (result, now + self.next_poll(now))
+    }
+
+    /// Invokes the sync hook, if any.
+    fn notify_sync(&self, result: &SyncResult) {
+        if let Some(on_sync) = self.on_sync {
+            on_sync(result);
+        }
+    }
+
+    /// Invokes the error hook, if any.
+    fn notify_error(&self, err: &SntpError) {
+        if let Some(on_error) = self.on_error {
+            on_error(err);
+        }
+    }
+
+    /// Sends and receives packets on the client socket.
+    fn dispatch(&mut self, sockets: &mut SocketSet, now: Instant) -> Result<Option<SyncResult>> {
+        let udp_handle = self.udp_handle.ok_or(SntpError::InvalidSocket)?;
+        let mut socket = sockets.get::<UdpSocket>(udp_handle);
+
+        check_buffers(&socket)?;
+
+        // Bind the socket if necessary
+        if !socket.is_open() {
+            if !self.auto_bind {
+                net_debug!("SNTP socket not bound");
+                return Err(SntpError::NotBound);
+            }
+            socket.bind(IpEndpoint {
+                addr: IpAddress::Unspecified,
+                port: SNTP_PORT,
+            })?;
+        }
+
+        if let Some(result) = self.receive_from(&mut socket, now)? {
+            return Ok(Some(result));
+        }
+        drop(socket);
+
+        if let Some(rx_handle) = self.rx_handle {
+            let mut rx_socket = sockets.get::<UdpSocket>(rx_handle);
+            if !rx_socket.is_open() {
+                net_debug!("SNTP receive socket not bound");
+                return Err(SntpError::NotBound);
+            }
+            if let Some(result) = self.receive_from(&mut rx_socket, now)? {
+                return Ok(Some(result));
+            }
+        }
+
+        let mut socket = sockets.get::<UdpSocket>(udp_handle);
+        let can_send = socket.can_send();
+        self.send_requests(now, can_send, &mut |bytes, endpoint| {
+            socket.send_slice(bytes, endpoint).map_err(SntpError::from)
+        })
+    }
+
+    /// Processes the packets received on `socket`, up to the budget or the
+    /// first valid response.
+    ///
+    /// Invalid packets are counted and skipped, so that they can't keep valid
+    /// responses from being processed nor requests from being sent: only
+    /// socket errors are returned.
+    fn receive_from(&mut self, socket: &mut UdpSocket, now: Instant) -> Result<Option<SyncResult>> {
+        for _ in 0..self.packet_budget {
+            match socket.recv() {
+                Ok((payload, endpoint)) => match self.process_response(payload, endpoint, now) {
+                    Ok(Some(result)) => return Ok(Some(result)),
+                    Ok(None) => (),
+                    Err(err) => self.notify_error(&err),
+                },
+                Err(Error::Exhausted) => break,
+                Err(e) => {
+                    self.on_network_error(now);
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Processes a packet received from `source` by another transport.
+    ///
+    /// This is the receiving half of [`poll`], for clients created with
+    /// [`without_socket`]: `data` is the UDP payload, and `now` the time it
+    /// was received. If it is a valid response, the outcome of the
+    /// synchronization is returned.
+    ///
+    /// Returns an error if the packet is not a response to an SNTP request,
+    /// or `Ok(None)` if it is not a valid one. Client-mode requests of other
+    /// hosts are dropped and counted in [`Stats::client_requests`].
+    ///
+    /// Errors are counted as with [`poll`]: in [`Stats::oversized`] for packets
+    /// too large, and in [`Stats::invalid_header`] otherwise.
+    ///
+    /// [`poll`]: #method.poll
+    /// [`without_socket`]: #method.without_socket
+    /// [`Stats::client_requests`]: struct.Stats.html#structfield.client_requests
+    /// [`Stats::oversized`]: struct.Stats.html#structfield.oversized
+    /// [`Stats::invalid_header`]: struct.Stats.html#structfield.invalid_header
+    pub fn process_response(
+        &mut self,
+        data: &[u8],
+        source: IpEndpoint,
+        now: Instant,
+    ) -> Result<Option<SyncResult>> {
+        let result = self.receive(data, source, now)?;
+        if result.is_some() {
+            self.on_result(now);
+        }
+        Ok(result)
+    }
+
+    /// Sends the requests that are due through another transport.
+    ///
+    /// This is the sending half of [`poll`], for clients created with
+    /// [`without_socket`]: `send` is called with the UDP payload of each
+    /// request and its destination, and should return
+    /// `Err(SntpError::Network(Error::Exhausted))` if the packet can't be
+    /// queued right now, in which case the request is retried on the next call.
+    ///
+    /// In [`QueryMode::Concurrent`], the outcome of the synchronization is
+    /// returned once the replies collected from the servers are combined.
+    ///
+    /// [`poll`]: #method.poll
+    /// [`without_socket`]: #method.without_socket
+    /// [`QueryMode::Concurrent`]: enum.QueryMode.html#variant.Concurrent
+    pub fn poll_requests<F>(&mut self, now: Instant, mut send: F) -> Result<Option<SyncResult>>
+    where
+        F: FnMut(&[u8], IpEndpoint) -> Result<()>,
+    {
+        self.send_requests(now, true, &mut send)
+    }
+
+    /// Updates the schedule after a valid response.
+    fn on_result(&mut self, now: Instant) {
+        // Increase the request interval to its maximum
+        self.on_response_accepted();
+        self.next_request = now + self.effective_poll_interval();
+    }
+
+    /// Combines the replies collected so far once the window has elapsed,
+    /// and sends the requests that are due if `can_send` is true.
+    fn send_requests<F>(
+        &mut self,
+        now: Instant,
+        can_send: bool,
+        send: &mut F,
+    ) -> Result<Option<SyncResult>>
+    where
+        F: FnMut(&[u8], IpEndpoint) -> Result<()>,
+    {
+        if self.collect_until.map_or(false, |until| now >= until) {
+            if let Some(result) = self.combine_replies(now) {
+                self.on_result(now);
+                return Ok(Some(result));
+            }
+        }
+
+        if !(self.link_up && can_send && now >= self.next_request_at()) {
+            return Ok(None);
+        }
+
+        // The timeout has expired.
+        // Send a request, set the timeout and increment interval using exponential backoff.
+        self.account_unanswered();
+        self.servers.clear_requests();
+        let sent = match self.query_mode {
+            QueryMode::Failover => match self.select_server() {
+                Some(server) => self.request(send, server, now),
+                None => {
+                    net_debug!("SNTP no server available");
+                    return Err(SntpError::NoServersAvailable);
+                }
+            },
+            QueryMode::Concurrent if self.servers.select(FAILOVER_THRESHOLD).is_some() => {
+                self.request_all(send, now)
+            }
+            QueryMode::Concurrent => {
+                net_debug!("SNTP no server available");
+                return Err(SntpError::NoServersAvailable);
+            }
+        };
+        match sent {
+            Ok(()) => (),
+            Err(SntpError::Network(Error::Exhausted)) => {
+                // The buffer is large enough (see `check_buffers`), but
+                // currently full: try again on the next poll.
+                net_debug!("SNTP tx buffer full, deferring request");
+                return Ok(None);
+            }
+            Err(e) => {
+                self.on_network_error(now);
+                return Err(e);
+            }
+        }
+        self.network_errors = 0;
+        self.network_backoff = None;
+        self.network_retry_at = None;
+        self.on_request_sent();
+        if self.query_mode == QueryMode::Concurrent {
+            self.collect_until = Some(now + COLLECT_WINDOW);
+        }
+        self.last_request = Some(now);
+        self.next_request = now + self.retry_interval();
+        self.curr_interval = self.clamp_interval(self.curr_interval * 2);
+        Ok(None)
+    }
+
+    /// Selects the server to send the next request to in failover mode, and
+    /// invokes the switch hook if it isn't the active one anymore.
+    fn select_server(&mut self) -> Option<IpAddress> {
+        let previous = self
+            .servers
+            .current()
+            .map(|entry| (entry.addr, entry.blocked));
+        let server = self.servers.select(self.failover_threshold())?;
+        if let (Some((old, blocked)), Some(on_switch)) = (previous, self.on_switch) {
+            if old != server {
+                let reason = blocked.map_or(SwitchReason::Unreachable, SwitchReason::Blocked);
+                on_switch(old, server, reason);
+            }
+        }
+        Some(server)
+    }
+
+    /// Returns the number of consecutive unanswered requests after which the
+    /// client switches to the next server.
+    fn failover_threshold(&self) -> u8 {
+        match self.retry_policy {
+            RetryPolicy::SameServer => FAILOVER_THRESHOLD,
+            RetryPolicy::NextServer => 1,
+        }
+    }
+
+    /// Records the outstanding request, if any, as unanswered.
+    fn account_unanswered(&mut self) {
+        if !self.outstanding {
+            return;
+        }
+        self.outstanding = false;
+        self.unanswered = self.unanswered.saturating_add(1);
+
+        for entry in self.servers.iter_mut().filter(|entry| entry.is_pending()) {
+            entry.failures = entry.failures.saturating_add(1);
+        }
+
+        if self.state == SyncState::Synced && self.unanswered >= self.holdover_threshold {
+            net_debug!("SNTP server not answering, entering holdover");
+            self.state = SyncState::Holdover;
+        }
+    }
+
+    /// Updates the synchronization state after a request has been sent.
+    fn on_request_sent(&mut self) {
+        self.outstanding = true;
+
+        if self.state == SyncState::Unsynced {
+            self.state = SyncState::Syncing;
+        }
+    }
+
+    /// Updates the synchronization state after a valid response has been received.
+    fn on_response_accepted(&mut self) {
+        self.outstanding = false;
+        self.unanswered = 0;
+        let warming_up = self.min_samples > 1 && self.accepted < self.min_samples;
+        if !warming_up {
+            self.state = SyncState::Synced;
+        } else if self.state == SyncState::Unsynced {
+            self.state = SyncState::Syncing;
+        }
+    }
+
+    /// Combines the replies collected in concurrent mode, if any, and closes the round.
+    ///
+    /// With at least three replies, the servers whose offset differs from the
+    /// median by more than the sum of both maximum errors are marked as
+    /// falsetickers. Among the others, the reply with the median offset is
+    /// selected.
+    fn combine_replies(&mut self, now: Instant) -> Option<SyncResult> {
+        self.collect_until = None;
+
+        let mut replies = [None; MAX_SERVERS];
+        let mut count = 0;
+        for reply in self.servers.iter().filter_map(|entry| entry.reply) {
+            replies[count] = Some(reply);
+            count += 1;
+        }
+        let median = median_reply(&mut replies[..count])?;
+
+        let mut truechimers = [None; MAX_SERVERS];
+        let mut agreeing = 0;
+        for entry in self.servers.iter_mut() {
+            if let Some(reply) = entry.reply.take() {
+                let distance = (reply.offset - median.offset).abs();
+                entry.falseticker = count >= 3 && distance > reply.max_error + median.max_error;
+                if entry.falseticker {
+                    net_debug!("SNTP server {} is a falseticker", entry.addr);
+                } else {
+                    truechimers[agreeing] = Some(reply);
+                    agreeing += 1;
+                }
+            }
+        }
+        let result = median_reply(&mut truechimers[..agreeing])?;
+
+        net_debug!("SNTP combined {} of {} replies", agreeing, count);
+        Some(self.accept_sample(result, now))
+    }
+
+    /// Stores the sample of an accepted result, and returns the result to report.
+    fn accept_sample(&mut self, mut result: SyncResult, now: Instant) -> SyncResult {
+        self.external_step = None;
+        if self.seeded {
+            self.filter.clear();
+            self.seeded = false;
+        } else if let Some(predicted) = self.filter.offset_at(now) {
+            let residual = result.offset - predicted;
+            let step_threshold = match self.primary_step_threshold {
+                Some(threshold) if result.stratum == 1 => threshold,
+                _ => self.step_threshold,
+            };
+            net_trace!(
+                "SNTP step threshold {} for stratum {}",
+                step_threshold,
+                result.stratum
+            );
+            let threshold = step_threshold.total_millis() as i64 * 1_000 + result.max_error;
+            if residual.abs() > threshold {
+                net_debug!(
+                    "SNTP offset jumped by {} us, above {} (stratum {})",
+                    residual,
+                    step_threshold,
+                    result.stratum
+                );
+                self.external_step = Some(residual);
+                self.filter.clear();
+            }
+        }
+        self.filter.push(Sample {
+            offset: result.offset,
+            delay: result.delay,
+            received_at: now,
+        });
+        self.last_sync = Some(now);
+        self.last_max_error = result.max_error;
+        self.last_root_delay = result.root_delay;
+        self.last_stratum = result.stratum;
+        self.last_server = result.server;
+        self.accepted = self.accepted.saturating_add(1).min(self.min_samples);
+        result.provisional = self.accepted < self.min_samples;
+
+        if self.sample_selection == SampleSelection::Filtered {
+            if let Some(best) = self.filter.best() {
+                result.offset = best.offset;
+                result.delay = best.delay;
+            }
+        }
+        result
+    }
+
+    /// Returns the bits of the fraction compared when matching originate
+    /// timestamps: the significant ones (see [`ORIGIN_FRAC_MASK`]), and the
+    /// sequence number if requests are tagged.
+    fn origin_mask(&self) -> u32 {
+        if self.sequence_tagging {
+            ORIGIN_FRAC_MASK | SEQUENCE_MASK
+        } else {
+            ORIGIN_FRAC_MASK
+        }
+    }
+
+    /// Checks the ranges and the consistency of the fields of a response,
+    /// counting the failures in the stats.
+    fn is_consistent(&mut self, repr: &Repr) -> bool {
+        if !(MIN_SANE_PRECISION..=MAX_SANE_PRECISION).contains(&repr.precision) {
+            net_debug!("SNTP response with invalid precision {}", repr.precision);
+            self.stats.invalid_precision += 1;
+            return false;
+        }
+        if !(MIN_SANE_POLL..=MAX_SANE_POLL).contains(&repr.poll_interval) {
+            net_debug!("SNTP response with invalid poll {}", repr.poll_interval);
+            self.stats.invalid_poll += 1;
+            return false;
+        }
+        let is_code = |code: &[u8; 4]| code.iter().all(|&c| c == 0 || (0x20..0x7f).contains(&c));
+        if repr.stratum == Stratum::Primary && !is_code(&repr.ref_identifier) {
+            net_debug!("SNTP primary server without a reference code");
+            self.stats.invalid_reference += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Checks that a response doesn't come from a server synchronized to itself
+    /// or to the future, counting the failures in the stats.
+    fn is_loop_free(&mut self, repr: &Repr, source: IpAddress) -> bool {
+        let self_reference = match (repr.stratum, source) {
+            (Stratum::Secondary(_), IpAddress::Ipv4(addr)) => repr.ref_identifier == addr.0,
+            _ => false,
+        };
+        if self_reference {
+            net_debug!("SNTP server {} references itself", source);
+            self.stats.self_reference += 1;
+            return false;
+        }
+        let never_synced = repr.ref_timestamp == Timestamp { sec: 0, frac: 0 };
+        if !never_synced && repr.xmit_timestamp.micros_since(repr.ref_timestamp) < 0 {
+            net_debug!("SNTP server {} reference timestamp in the future", source);
+            self.stats.future_reference += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Processes a response from the SNTP server.
+    ///
+    /// Returns an error if the packet is not a response to an SNTP request,
+    /// or `Ok(None)` if it is not a valid one.
+    fn receive(
+        &mut self,
+        data: &[u8],
+        source: IpEndpoint,
+        now: Instant,
+    ) -> Result<Option<SyncResult>> {
+        // A socket bound to the NTP port also receives the requests of other
+        // clients, which can't be answered by a client
+        let mode = data.first().map(|&flags| unpack_flags(flags).2);
+        if mode == Some(ProtocolMode::Client) {
+            net_debug!("SNTP client request from {}, dropping", source);
+            self.stats.client_requests += 1;
+            return Ok(None);
+        }
+
+        if !self.is_trusted(source.addr) {
+            net_debug!("SNTP packet from untrusted source {}", source);
+            self.stats.untrusted_source += 1;
+            return Ok(None);
+        }
+
+        // Only accept the response to our outstanding request: it must come from
+        // a server we sent it to, and echo our transmit timestamp.
+        let entry = match self.servers.get(source.addr) {
+            Some(entry) if entry.sent_at.is_some() && source.port == SNTP_PORT => *entry,
+            _ => {
+                net_debug!("SNTP response from unexpected source {}", source);
+                self.stats.wrong_source += 1;
+                return Ok(None);
+            }
+        };
+
+        if data.len() > self.max_packet_len {
+            net_debug!("SNTP packet too large: {} bytes", data.len());
+            self.stats.oversized += 1;
+            return Err(SntpError::PacketTooLarge);
+        }
+
+        // Cheaply discard packets that can't be SNTP responses before parsing them
+        let sntp_packet = match Packet::new_checked(data) {
+            Ok(sntp_packet) => sntp_packet,
+            Err(e) => {
+                net_debug!("SNTP invalid pkt: {:?}", e);
+                self.stats.filtered += 1;
+                return Ok(None);
+            }
+        };
+
+        if let Err(err) = validate_header(&sntp_packet) {
+            self.stats.invalid_header += 1;
+            return Err(err);
+        }
+
+        if let Stratum::Reserved(stratum) = sntp_packet.stratum() {
+            if stratum > MAX_STRATUM {
+                net_debug!("SNTP invalid stratum {}", stratum);
+                self.stats.filtered += 1;
+                return Ok(None);
+            }
+        }
+
+        let sntp_repr = match Repr::parse(&sntp_packet) {
+            Ok(sntp_repr) => sntp_repr,
+            Err(e) => {
+                net_debug!("SNTP error parsing pkt: {:?}", e);
+                return Ok(None);
+            }
+        };
+        let previous = entry.previous.map(|exchange| exchange.client_transmit);
+        let mask = self.origin_mask();
+        let answers = |sent: Option<Timestamp>| {
+            sent.map_or(false, |sent| {
+                sntp_repr.is_masked_response_to(&sent_request(sent), mask)
+            })
+        };
+        if !answers(entry.request_timestamp)
+            && entry.request_timestamp.is_some()
+            && answers(previous)
+        {
+            // Interleaved servers echo the transmit timestamp of the previous
+            // request, to report the precise transmit time of their previous
+            // response. Only basic mode is implemented for now.
+            net_debug!("SNTP interleaved response, ignoring");
+            self.stats.interleaved += 1;
+            return Ok(None);
+        }
+        if !answers(entry.request_timestamp) {
+            net_debug!("SNTP response with unexpected originate timestamp");
+            self.stats.wrong_origin += 1;
+            return Ok(None);
+        }
+
+        self.last_repr = Some(sntp_repr);
+
+        if sntp_repr.stratum == Stratum::KissOfDeath {
+            let code = sntp_repr.ref_identifier;
+            match &code {
+                b"DENY" | b"RSTR" => {
+                    net_debug!("SNTP access denied by {}, blocking server", source.addr);
+                    if let Some(entry) = self.servers.get_mut(source.addr) {
+                        entry.blocked = Some(BlockReason::KissOfDeath(code));
+                    }
+                }
+                b"RATE" => {
+                    let backoff = poll_interval(self.maxpoll);
+                    net_debug!(
+                        "SNTP rate limited by {}, backing off for {}",
+                        source.addr,
+                        backoff
+                    );
+                    self.rate_limited_until = Some(now + backoff);
+                }
+                _ => {
+                    net_debug!("SNTP kiss o' death received, doing nothing");
+                }
+            }
+            self.notify_error(&SntpError::KissOfDeath(code));
+            return Ok(None);
+        }
+
+        if self.strict_validation && !self.is_consistent(&sntp_repr) {
+            return Ok(None);
+        }
+        if self.loop_detection && !self.is_loop_free(&sntp_repr, source.addr) {
+            return Ok(None);
+        }
+
+        // MACs aren't verified yet
+        let authenticated = false;
+        if self.require_auth && !authenticated {
+            net_debug!("SNTP unauthenticated response from {}", source.addr);
+            self.stats.unauthenticated += 1;
+            return Ok(None);
+        }
+
+        let unsynchronized = sntp_repr.leap_indicator == LeapIndicator::AlarmCondition;
+        if self.allowed_leap & leap_mask(sntp_repr.leap_indicator) == 0 {
+            if unsynchronized {
+                net_debug!("SNTP server {} is unsynchronized", source.addr);
+                self.stats.unsynchronized += 1;
+            } else {
+                net_debug!(
+                    "SNTP leap indicator {:?} not allowed",
+                    sntp_repr.leap_indicator
+                );
+                self.stats.disallowed_leap += 1;
+            }
+            return Ok(None);
+        }
+
+        let processing_delay = sntp_repr
+            .xmit_timestamp
+            .micros_since(sntp_repr.recv_timestamp);
+
+        if let Some(max) = self.max_processing_delay {
+            if processing_delay < 0 || processing_delay as u64 > max.total_millis() * 1_000 {
+                net_debug!(
+                    "SNTP server processing delay too large: {}us",
+                    processing_delay
+                );
+                self.stats.processing_delay_rejected += 1;
+                return Ok(None);
+            }
+        }
+
+        let server_sync_age = if sntp_repr.ref_timestamp == (Timestamp { sec: 0, frac: 0 }) {
+            None
+        } else {
+            Some(
+                sntp_repr
+                    .xmit_timestamp
+                    .micros_since(sntp_repr.ref_timestamp),
+            )
+        };
+
+        if let Some(max) = self.max_server_sync_age {
+            let max = max.total_millis() as i64 * 1_000;
+            if server_sync_age.map_or(true, |age| age > max) {
+                net_debug!("SNTP server {} synchronized too long ago", source.addr);
+                self.stats.stale_server += 1;
+                return Ok(None);
+            }
+        }
+
+        // Perform conversion from NTP timestamp to Unix timestamp, resolving
+        // the NTP era with our current estimate, if any
+        let estimate = self
+            .filter
+            .offset_at(now)
+            .map(|offset| micros(now) + offset);
+        let server_micros = match estimate {
+            Some(estimate) => sntp_repr.xmit_timestamp.to_unix_micros_near(estimate),
+            None => sntp_repr.xmit_timestamp.to_unix_micros(),
+        };
+
+        // Once we know the time, discard responses too far from it
+        if let (Some(window), Some(estimate)) = (self.acceptance_window, estimate) {
+            if (server_micros - estimate).unsigned_abs() > window.total_millis() * 1_000 {
+                net_debug!(
+                    "SNTP response {} us away from the local estimate",
+                    server_micros - estimate
+                );
+                self.stats.outside_window += 1;
+                return Ok(None);
+            }
+        }
+
+        // Only accepted responses may change the polling schedule
+        self.server_poll = Some(sntp_repr.poll_interval);
+        self.curr_interval = self.clamp_interval(self.curr_interval);
+        let unix_seconds = server_micros.div_euclid(1_000_000).max(0) as u64;
+
+        // Round-trip delay, excluding the time spent by the server
+        let round_trip = match entry.sent_at {
+            Some(sent_at) => micros(now) - micros(sent_at),
+            None => 0,
+        };
+        let delay = (round_trip - processing_delay).max(0);
+
+        // The server clock read T3 + delay/2 when the response was received
+        let offset = server_micros + delay / 2 - micros(now);
+
+        // Synchronization distance: errors accumulated by the server, half the
+        // round trip, and the resolution of both clocks
+        let local_precision = log2_secs_to_micros(self.precision).max(self.resolution.unwrap_or(0));
+        let max_error = short_to_micros(i64::from(sntp_repr.root_dispersion))
+            + short_to_micros(i64::from(sntp_repr.root_delay.max(0))) / 2
+            + delay / 2
+            + log2_secs_to_micros(sntp_repr.precision)
+            + local_precision;
+
+        // Don't report more precision than the local clock has
+        let (offset, delay) = match self.resolution {
+            Some(resolution) => (round_to(offset, resolution), round_to(delay, resolution)),
+            None => (offset, delay),
+        };
+
+        let result = SyncResult {
+            timestamp: unix_seconds as u32,
+            unix_seconds,
+            server_timestamp: sntp_repr.xmit_timestamp,
+            processing_delay,
+            server_sync_age,
+            offset,
+            delay,
+            root_delay: short_to_micros(i64::from(sntp_repr.root_delay.max(0))) + delay,
+            max_error,
+            unsynchronized,
+            provisional: false,
+            stratum: sntp_repr.stratum.into(),
+            authenticated,
+            server: source.addr,
+        };
+
+        // Further responses to the same request are duplicates
+        let entry = self.servers.get_mut(source.addr).unwrap();
+        entry.request_timestamp = None;
+        entry.failures = 0;
+        entry.previous = Some(Exchange {
+            client_transmit: sntp_repr.orig_timestamp,
+            server_receive: sntp_repr.recv_timestamp,
+            server_transmit: sntp_repr.xmit_timestamp,
+            client_receive: now,
+        });
+
+        match self.query_mode {
+            QueryMode::Failover => Ok(Some(self.accept_sample(result, now))),
+            QueryMode::Concurrent => {
+                entry.reply = Some(result);
+                if self.servers.iter().any(|entry| entry.is_pending()) {
+                    Ok(None)
+                } else {
+                    Ok(self.combine_replies(now))
+                }
+            }
+        }
+    }
+
+    /// Sends a request to every server that isn't blocked.
+    ///
+    /// Servers that can't be sent a request, e.g. because the transmit buffer
+    /// is full, are skipped. Returns the first error if no request was sent.
+    fn request_all<F>(&mut self, send: &mut F, now: Instant) -> Result<()>
+    where
+        F: FnMut(&[u8], IpEndpoint) -> Result<()>,
+    {
+        let mut servers = [None; MAX_SERVERS];
+        for (slot, entry) in servers.iter_mut().zip(self.servers.iter()) {
+            if entry.blocked.is_none() {
+                *slot = Some(entry.addr);
+            }
+        }
+
+        let mut sent = false;
+        let mut error = None;
+        for &server in servers.iter().flatten() {
+            match self.request(send, server, now) {
+                Ok(()) => sent = true,
+                Err(e) => {
+                    net_debug!("SNTP request to {} failed: {}", server, e);
+                    error = error.or(Some(e));
+                }
+            }
+        }
+
+        match error {
+            Some(e) if !sent => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Builds the request to send at the given time.
+    fn request_repr(&self, now: Instant) -> Repr {
+        // Our best estimate of the current time, used as transmit timestamp (T1)
+        let offset = self.filter.offset_at(now).unwrap_or(0);
+        let mut xmit_timestamp = Timestamp::from_unix_micros(micros(now) + offset);
+        if self.sequence_tagging {
+            xmit_timestamp.frac = (xmit_timestamp.frac & !SEQUENCE_MASK) | u32::from(self.sequence);
+        }
+
+        // Let the server know how often we are going to poll it, i.e. the
+        // maximum poll interval once synchronized
+        let poll_interval = duration_to_log2_secs(self.effective_poll_interval()).max(0) as u8;
+
+        let leap_indicator = match self.leap_indicator {
+            Some(leap) if self.state == SyncState::Synced => leap,
+            Some(_) => LeapIndicator::AlarmCondition,
+            None => LeapIndicator::NoWarning,
+        };
+
+        Repr {
+            leap_indicator,
+            poll_interval,
+            precision: self.precision,
+            ..sent_request(xmit_timestamp)
+        }
+    }
+
+    /// Sends a request to the given SNTP server.
+    fn request<F>(&mut self, send: &mut F, server: IpAddress, now: Instant) -> Result<()>
+    where
+        F: FnMut(&[u8], IpEndpoint) -> Result<()>,
+    {
+        if self.sequence_tagging {
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+        let sntp_repr = self.request_repr(now);
+        let xmit_timestamp = sntp_repr.xmit_timestamp;
+
+        let endpoint = IpEndpoint {
+            addr: server,
+            port: SNTP_PORT,
+        };
+
+        net_trace!(
+            "SNTP send request to {}: {:?}",
+            endpoint,
+            sntp_repr.debug_compact()
+        );
+
+        if let Some(dscp) = self.dscp {
+            // The UDP socket of smoltcp 0.6 cannot mark packets, so this is a no-op.
+            net_trace!("SNTP DSCP {} requested but not supported by socket", dscp);
+        }
+
+        let mut packet = [0; MIN_BUFFER_LEN];
+        let len = sntp_repr.emit_to_bytes(&mut packet)?;
+        send(&packet[..len], endpoint)?;
+        self.last_raw_request = Some(packet);
+
+        if let Some(entry) = self.servers.get_mut(server) {
+            entry.sent_at = Some(now);
+            entry.request_timestamp = Some(xmit_timestamp);
+        }
+
+        Ok(())
+    }
+}
+
+/// Ensures that a packet is a server response of a supported protocol version.
+///
+/// This only looks at the first octet, so that unrelated packets are discarded
+/// before being fully parsed.
+fn validate_header(packet: &Packet<&[u8]>) -> Result<()> {
+    let mode = packet.protocol_mode();
+    if mode != ProtocolMode::Server {
+        net_debug!("Invalid mode in SNTP response: {:?}", mode);
+        return Err(SntpError::UnexpectedMode(mode));
+    }
+
+    let version = packet.version();
+    if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+        net_debug!("Invalid version in SNTP response: {}", version);
+        return Err(SntpError::UnsupportedVersion(version));
+    }
+
+    Ok(())
+}
+
+/// Returns the reply with the median offset, the lower one if there is an
+/// even number of replies.
+fn median_reply(replies: &mut [Option<SyncResult>]) -> Option<SyncResult> {
+    replies.sort_unstable_by_key(|reply| reply.map(|r| r.offset));
+    replies
+        .get((replies.len().max(1) - 1) / 2)
+        .copied()
+        .flatten()
+}
+
+/// Bits of the fraction compared when matching originate timestamps.
+///
+/// The transmit timestamps of requests have a microsecond resolution, which
+/// only needs the 20 most significant bits of the fraction (2^-20 s is about
+/// 0.95 us): the lower bits are ignored, so that replies aren't rejected if
+/// they were altered on the way, e.g. by a server or a middlebox rounding them.
+const ORIGIN_FRAC_MASK: u32 = 0xffff_f000;
+
+/// Bits of the fraction of the transmit timestamp carrying the sequence number.
+const SEQUENCE_MASK: u32 = 0x0000_00ff;
+
+/// Returns the request sent with the transmit timestamp `xmit_timestamp`, as
+/// far as responses are concerned: see [`Repr::is_response_to`].
+///
+/// [`Repr::is_response_to`]: wire/struct.Repr.html#method.is_response_to
+fn sent_request(xmit_timestamp: Timestamp) -> Repr {
+    Repr {
+        leap_indicator: LeapIndicator::NoWarning,
+        version: NTP_VERSION_4,
+        protocol_mode: ProtocolMode::Client,
+        stratum: Stratum::KissOfDeath,
+        poll_interval: 0,
+        precision: 0,
+        // A client isn't a time source
+        root_delay: 0,
+        root_dispersion: 0,
+        ref_identifier: [0, 0, 0, 0],
+        ref_timestamp: Timestamp { sec: 0, frac: 0 },
+        orig_timestamp: Timestamp { sec: 0, frac: 0 },
+        recv_timestamp: Timestamp { sec: 0, frac: 0 },
+        xmit_timestamp,
+    }
+}
+
+/// Returns the bit representing a leap indicator in a set of allowed ones.
+fn leap_mask(li: LeapIndicator) -> u8 {
+    match u8::from(li) {
+        value @ 0..=3 => 1 << value,
+        _ => 0,
+    }
+}
+
+/// Rounds a value to the nearest multiple of `resolution`, halfway cases away from zero.
+fn round_to(value: i64, resolution: i64) -> i64 {
+    let rounded = (value.abs() + resolution / 2) / resolution * resolution;
+    if value < 0 {
+        -rounded
+    } else {
+        rounded
+    }
+}
+
+/// Converts an instant to microseconds.
+fn micros(instant: Instant) -> i64 {
+    instant.total_millis() * 1_000
+}
+
+/// Converts a poll exponent (log2 seconds) to a `Duration`.
+fn poll_interval(exponent: u8) -> Duration {
+    log2_secs_to_duration(exponent.min(MAX_POLL_EXPONENT) as i8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::socket::UdpPacketMetadata;
+    use crate::server::Server;
+    use crate::wire::test::PACKET_BYTES;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn leak<T>(items: Vec<T>) -> &'static mut [T] {
+        Box::leak(items.into_boxed_slice())
+    }
+
+    fn server() -> IpAddress {
+        IpAddress::v4(192, 168, 1, 1)
+    }
+
+    fn server_endpoint() -> IpEndpoint {
+        IpEndpoint::new(server(), SNTP_PORT)
+    }
+
+    fn client() -> (SocketSet<'static, 'static, 'static>, Client) {
+        let mut sockets = SocketSet::new(leak((0..4).map(|_| None).collect()));
+        let rx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        let tx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        let client = Client::new(
+            &mut sockets,
+            rx_buffer,
+            tx_buffer,
+            IpAddress::v4(192, 168, 1, 1),
+            Instant::from_secs(0),
+        );
+        (sockets, client)
+    }
+
+    /// Pretends a request matching `PACKET_BYTES` was sent to `server()`.
+    fn expect_response(client: &mut Client) {
+        client.on_request_sent();
+        let entry = client.servers.get_mut(server()).unwrap();
+        entry.sent_at = Some(Instant::from_secs(0));
+        entry.request_timestamp = Some(Timestamp { sec: 0, frac: 0 });
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        let (_, mut client) = client();
+        assert_eq!(client.state(), SyncState::Unsynced);
+
+        client.on_request_sent();
+        assert_eq!(client.state(), SyncState::Syncing);
+
+        client.on_response_accepted();
+        assert_eq!(client.state(), SyncState::Synced);
+
+        client.on_request_sent();
+        for _ in 1..DEFAULT_HOLDOVER_THRESHOLD {
+            client.account_unanswered();
+            client.on_request_sent();
+            assert_eq!(client.state(), SyncState::Synced);
+        }
+        client.account_unanswered();
+        assert_eq!(client.state(), SyncState::Holdover);
+
+        client.on_response_accepted();
+        assert_eq!(client.state(), SyncState::Synced);
+    }
+
+    #[test]
+    fn test_time_since_last_sync() {
+        let (_, mut client) = client();
+        let now = Instant::from_secs(100);
+        assert_eq!(client.time_since_last_sync(now), None);
+
+        client.seed_offset(1_589_753_489_000_000, now);
+        assert_eq!(client.time_since_last_sync(now), None);
+
+        expect_response(&mut client);
+        client
+            .receive(&PACKET_BYTES[..], server_endpoint(), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.time_since_last_sync(now),
+            Some(Duration::from_millis(0))
+        );
+        assert_eq!(
+            client.time_since_last_sync(now + Duration::from_secs(3_600)),
+            Some(Duration::from_secs(3_600))
+        );
+    }
+
+    #[test]
+    fn test_sync_distance() {
+        let (_, mut client) = client();
+        let now = Instant::from_secs(0);
+        let max = Duration::from_millis(100);
+        assert_eq!(client.sync_distance(now), None);
+        assert!(!client.is_time_trustworthy(now, max));
+
+        expect_response(&mut client);
+        let result = client
+            .process_response(&PACKET_BYTES[..], server_endpoint(), now)
+            .unwrap()
+            .unwrap();
+        let distance = client.sync_distance(now).unwrap();
+        assert_eq!(distance, result.max_error());
+        assert!(client.is_time_trustworthy(now, max));
+        assert!(!client.is_time_trustworthy(now, distance - Duration::from_millis(1)));
+
+        // 15 ppm of dispersion over 1000 s
+        let later = now + Duration::from_secs(1_000);
+        assert_eq!(
+            client.sync_distance(later),
+            Some(distance + Duration::from_millis(15))
+        );
+        client.state = SyncState::Holdover;
+        assert!(client.is_time_trustworthy(later, max));
+        assert!(!client.is_time_trustworthy(now + Duration::from_secs(10_000), max));
+
+        assert_eq!(
+            client.time_with_uncertainty(later),
+            Some((
+                client.current_time(later).unwrap(),
+                distance + Duration::from_millis(15)
+            ))
+        );
+
+        client.set_min_samples(2);
+        assert!(!client.is_time_trustworthy(now, max));
+
+        client.seed_offset(1_589_753_000_000_000, now);
+        assert_eq!(client.time_with_uncertainty(now), None);
+    }
+
+    #[test]
+    fn test_receive() {
+        let (_, mut client) = client();
+        expect_response(&mut client);
+        let result = client
+            .receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.timestamp, 1589753489);
+        assert_eq!(
+            result.server_ntp_timestamp(),
+            Repr::parse_bytes(&PACKET_BYTES[..]).unwrap().xmit_timestamp
+        );
+        assert_eq!(result.processing_delay_micros(), 16);
+        assert_eq!(
+            client.last_repr(),
+            Some(&Repr::parse_bytes(&PACKET_BYTES[..]).unwrap())
+        );
+        assert_eq!(result.offset(), Duration::from_millis(1_589_753_489_416));
+        // 1693 us root dispersion, 2197 us half root delay, 1 + 977 us precision
+        assert_eq!(result.max_error, 4_868);
+        assert_eq!(result.max_error(), Duration::from_millis(5));
+        assert_eq!(client.samples().count(), 1);
+
+        client.set_max_processing_delay(None);
+        client.set_clock_resolution(Duration::from_millis(10));
+        expect_response(&mut client);
+        let result = client
+            .receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.offset_micros(), 1_589_753_489_420_000);
+        assert_eq!(result.max_error, 13_891);
+
+        client.set_max_processing_delay(Some(Duration::from_millis(0)));
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.stats().processing_delay_rejected, 1);
+    }
+
+    #[test]
+    fn test_rejected_server_poll() {
+        let (_, mut client) = client();
+        let poll = Repr::parse_bytes(&PACKET_BYTES[..]).unwrap().poll_interval;
+
+        client.set_max_processing_delay(Some(Duration::from_millis(0)));
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.server_poll, None);
+
+        client.set_max_processing_delay(None);
+        client.set_max_server_sync_age(Some(Duration::from_secs(0)));
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.server_poll, None);
+
+        client.set_max_server_sync_age(None);
+        expect_response(&mut client);
+        assert!(client
+            .receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .is_some());
+        assert_eq!(client.server_poll, Some(poll));
+    }
+
+    #[test]
+    fn test_poll_bounds() {
+        let (_, mut client) = client();
+        assert_eq!(client.set_minpoll(3), Err(SntpError::InvalidPollInterval));
+        assert_eq!(client.set_maxpoll(18), Err(SntpError::InvalidPollInterval));
+        assert_eq!(
+            client.set_minpoll(DEFAULT_MAXPOLL + 1),
+            Err(SntpError::InvalidPollInterval)
+        );
+
+        client.set_minpoll(4).unwrap();
+        client.set_maxpoll(10).unwrap();
+        assert_eq!(client.set_maxpoll(3), Err(SntpError::InvalidPollInterval));
+        assert_eq!(
+            client.clamp_interval(Duration::from_secs(1)),
+            Duration::from_secs(16)
+        );
+        assert_eq!(
+            client.clamp_interval(Duration::from_secs(1 << 12)),
+            Duration::from_secs(1 << 10)
+        );
+
+        client.server_poll = Some(8);
+        assert_eq!(
+            client.clamp_interval(Duration::from_secs(16)),
+            Duration::from_secs(1 << 8)
+        );
+
+        assert_eq!(
+            client.set_poll_exponent(2),
+            Err(SntpError::InvalidPollInterval)
+        );
+        assert_eq!(
+            client.set_poll_exponent(18),
+            Err(SntpError::InvalidPollInterval)
+        );
+        client.server_poll = None;
+        client.set_poll_exponent(12).unwrap();
+        assert_eq!((client.minpoll, client.maxpoll), (12, 12));
+        assert_eq!(client.curr_interval, Duration::from_secs(1 << 12));
+        client.set_poll_exponent(4).unwrap();
+        assert_eq!(client.curr_interval, Duration::from_secs(16));
+        client.set_poll_exponent(3).unwrap();
+        assert_eq!((client.minpoll, client.maxpoll), (3, 3));
+        assert_eq!(client.curr_interval, Duration::from_secs(8));
+        assert_eq!(
+            client.next_poll(client.next_request),
+            Duration::from_millis(0)
+        );
+    }
+
+    #[test]
+    fn test_with_socket() {
+        let (mut sockets, client) = client();
+        assert!(Client::<4>::with_socket(
+            &sockets,
+            client.udp_handle.unwrap(),
+            IpAddress::v4(192, 168, 1, 1),
+            Instant::from_secs(0),
+        )
+        .is_ok());
+
+        sockets.remove(client.udp_handle.unwrap());
+        assert_eq!(
+            Client::<4>::with_socket(
+                &sockets,
+                client.udp_handle.unwrap(),
+                IpAddress::v4(192, 168, 1, 1),
+                Instant::from_secs(0),
+            )
+            .err(),
+            Some(SntpError::InvalidSocket)
+        );
+    }
+
+    #[test]
+    fn test_receive_socket() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(0);
+        let rx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        let tx_buffer = UdpSocketBuffer::new(leak(vec![]), leak(vec![]));
+        let rx_handle = sockets.add(UdpSocket::new(rx_buffer, tx_buffer));
+        assert_eq!(
+            client.set_receive_socket(&sockets, Some(rx_handle)),
+            Err(SntpError::NoPacketMetadata)
+        );
+        assert_eq!(client.rx_handle, None);
+
+        let rx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        let tx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        let rx_handle = sockets.add(UdpSocket::new(rx_buffer, tx_buffer));
+        client
+            .set_receive_socket(&sockets, Some(rx_handle))
+            .unwrap();
+
+        // The receive socket must be bound by the application
+        assert_eq!(client.poll(&mut sockets, now), Err(SntpError::NotBound));
+        assert_eq!(client.last_request, None);
+        sockets
+            .get::<UdpSocket>(rx_handle)
+            .bind(IpEndpoint::new(IpAddress::Unspecified, 1_123))
+            .unwrap();
+        assert_eq!(client.poll(&mut sockets, now), Ok(None));
+        assert_eq!(client.last_request, Some(now));
+        assert!(sockets.get::<UdpSocket>(rx_handle).can_send());
+        assert!(!sockets
+            .get::<UdpSocket>(client.udp_handle.unwrap())
+            .can_send());
+
+        client.set_receive_socket(&sockets, None).unwrap();
+        sockets.remove(rx_handle);
+        assert_eq!(
+            client.set_receive_socket(&sockets, Some(rx_handle)),
+            Err(SntpError::InvalidSocket)
+        );
+    }
+
+    #[test]
+    fn test_rebind() {
+        let now = Instant::from_secs(0);
+        let mut without: Client = Client::without_socket(server(), now);
+
+        // Sockets bound to a specific address can't be rebound
+        let (mut sockets, mut bound) = client();
+        sockets
+            .get::<UdpSocket>(bound.udp_handle.unwrap())
+            .bind(IpEndpoint::new(IpAddress::v4(192, 168, 1, 10), SNTP_PORT))
+            .unwrap();
+        assert_eq!(
+            bound.rebind(&mut sockets),
+            Err(SntpError::Network(Error::Illegal))
+        );
+        assert_eq!(without.rebind(&mut sockets), Err(SntpError::InvalidSocket));
+
+        let (mut sockets, mut client) = client();
+        client.poll(&mut sockets, now).unwrap();
+        assert!(client.outstanding);
+        client.rebind(&mut sockets).unwrap();
+        assert!(!client.outstanding);
+        assert!(client.servers.iter().all(|entry| !entry.is_pending()));
+    }
+
+    #[test]
+    fn test_buffer_too_small() {
+        let (mut sockets, _) = client();
+        let handle = sockets.add(UdpSocket::new(
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            UdpSocketBuffer::new(
+                leak(vec![UdpPacketMetadata::EMPTY; 1]),
+                leak(vec![0; MIN_BUFFER_LEN - 1]),
+            ),
+        ));
+        assert_eq!(
+            Client::<4>::with_socket(
+                &sockets,
+                handle,
+                IpAddress::v4(192, 168, 1, 1),
+                Instant::from_secs(0),
+            )
+            .err(),
+            Some(SntpError::BufferTooSmall)
+        );
+
+        let handle = sockets.add(UdpSocket::new(
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            UdpSocketBuffer::new(leak(vec![]), leak(vec![0; 128])),
+        ));
+        assert_eq!(
+            Client::<4>::with_socket(
+                &sockets,
+                handle,
+                IpAddress::v4(192, 168, 1, 1),
+                Instant::from_secs(0),
+            )
+            .err(),
+            Some(SntpError::NoPacketMetadata)
+        );
+    }
+
+    #[test]
+    fn test_try_new() {
+        fn buffer() -> UdpSocketBuffer<'static, 'static> {
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]))
+        }
+        let now = Instant::from_secs(0);
+
+        let mut sockets = SocketSet::new(leak((0..1).map(|_| None).collect()));
+        let client = Client::try_new(&mut sockets, buffer(), buffer(), server(), now).unwrap();
+        assert!(client.udp_handle.is_some());
+        assert_eq!(sockets.iter().count(), 1);
+
+        let unusable = UdpSocketBuffer::new(leak(vec![]), leak(vec![0; 128]));
+        let mut sockets = SocketSet::new(leak((0..1).map(|_| None).collect()));
+        assert_eq!(
+            Client::try_new(&mut sockets, unusable, buffer(), server(), now).err(),
+            Some(SntpError::NoPacketMetadata)
+        );
+        assert_eq!(sockets.iter().count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "adding a socket to a full SocketSet")]
+    fn test_new_full() {
+        let mut sockets = SocketSet::new(leak((0..0).map(|_| None).collect()));
+        Client::new(
+            &mut sockets,
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            IpAddress::v4(192, 168, 1, 1),
+            Instant::from_secs(0),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid SNTP socket buffers")]
+    fn test_new_without_metadata() {
+        let mut sockets = SocketSet::new(leak((0..1).map(|_| None).collect()));
+        Client::new(
+            &mut sockets,
+            UdpSocketBuffer::new(leak(vec![]), leak(vec![0; 128])),
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            IpAddress::v4(192, 168, 1, 1),
+            Instant::from_secs(0),
+        );
+    }
+
+    #[test]
+    fn test_tx_buffer_full() {
+        let (mut sockets, _) = client();
+        let handle = sockets.add(UdpSocket::new(
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 2]), leak(vec![0; 64])),
+        ));
+        let mut client =
+            Client::<4>::with_socket(&sockets, handle, server(), Instant::from_secs(0)).unwrap();
+
+        {
+            let mut socket = sockets.get::<UdpSocket>(handle);
+            socket.bind(SNTP_PORT).unwrap();
+            socket.send(MIN_BUFFER_LEN, server_endpoint()).unwrap();
+        }
+        assert_eq!(client.poll(&mut sockets, Instant::from_secs(0)), Ok(None));
+        assert_eq!(client.last_request, None);
+        assert_eq!(client.network_backoff(), None);
+    }
+
+    #[test]
+    fn test_error_hook() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+        static KOD: AtomicBool = AtomicBool::new(false);
+
+        let (_, mut client) = client();
+        client.set_on_error(Some(|err| {
+            KOD.store(*err == SntpError::KissOfDeath(*b"RATE"), Ordering::SeqCst)
+        }));
+
+        let mut bytes = PACKET_BYTES;
+        bytes[field::STRATUM] = 0;
+        bytes[field::REFERENCE_IDENTIFIER].copy_from_slice(b"RATE");
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert!(KOD.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_poll_skips_invalid_packets() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        static ERRORS: AtomicU32 = AtomicU32::new(0);
+
+        let now = Instant::from_secs(0);
+        let mut bad = PACKET_BYTES;
+        bad[field::LI_VN_MODE] = 0x25; // NTPv4, broadcast mode
+        let mut padded = [0; 60];
+        padded[..48].copy_from_slice(&PACKET_BYTES);
+        let mut rx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 4]), leak(vec![0; 256]));
+        for packet in [&bad[..], &padded[..], &bad[..], &PACKET_BYTES[..]] {
+            rx_buffer
+                .enqueue(packet.len(), server_endpoint())
+                .unwrap()
+                .copy_from_slice(packet);
+        }
+        let tx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        let mut sockets = SocketSet::new(leak((0..1).map(|_| None).collect()));
+        let mut client = Client::new(&mut sockets, rx_buffer, tx_buffer, server(), now);
+        client.set_max_packet_len(48);
+        client.set_on_error(Some(|_| {
+            ERRORS.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        // The valid response queued behind invalid packets is still processed
+        expect_response(&mut client);
+        assert!(client.poll(&mut sockets, now).unwrap().is_some());
+        assert_eq!(client.stats().invalid_header, 2);
+        assert_eq!(client.stats().oversized, 1);
+        assert_eq!(ERRORS.load(Ordering::SeqCst), 3);
+
+        // Invalid packets don't keep requests from being sent
+        let mut rx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        rx_buffer
+            .enqueue(bad.len(), server_endpoint())
+            .unwrap()
+            .copy_from_slice(&bad[..]);
+        let tx_buffer =
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128]));
+        let mut sockets = SocketSet::new(leak((0..1).map(|_| None).collect()));
+        let mut client = Client::new(&mut sockets, rx_buffer, tx_buffer, server(), now);
+        expect_response(&mut client);
+        let later = now + Duration::from_secs(3_600);
+        assert_eq!(client.poll(&mut sockets, later), Ok(None));
+        assert_eq!(client.stats().invalid_header, 1);
+        assert_eq!(client.last_request, Some(later));
+    }
+
+    #[test]
+    fn test_force_sync_rate_kod() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(0);
+        client.poll(&mut sockets, now).unwrap();
+        client.set_maxpoll(10).unwrap();
+
+        let mut bytes = PACKET_BYTES;
+        bytes[field::STRATUM] = 0;
+        bytes[field::REFERENCE_IDENTIFIER].copy_from_slice(b"RATE");
+        expect_response(&mut client);
+        client.receive(&bytes[..], server_endpoint(), now).unwrap();
+        assert_eq!(client.next_poll(now), poll_interval(10));
+
+        client.force_sync(now);
+        assert_eq!(client.next_poll(now), poll_interval(10));
+
+        client.force_sync_override(now);
+        assert_eq!(client.next_poll(now), Duration::from_millis(0));
+
+        // Blocked servers are never queried
+        client.block_server(server()).unwrap();
+        client.force_sync_override(now);
+        assert_eq!(
+            client.poll_requests(now, |_, _| panic!("unexpected request")),
+            Err(SntpError::NoServersAvailable)
+        );
+    }
+
+    #[test]
+    fn test_switch_hook() {
+        use core::sync::atomic::{AtomicU8, Ordering};
+        static SWITCHES: AtomicU8 = AtomicU8::new(0);
+        static BLOCKED: AtomicU8 = AtomicU8::new(0);
+
+        let (_, mut client) = client();
+        let backup = IpAddress::v4(192, 168, 1, 2);
+        client.add_server(backup).unwrap();
+        client.set_retry_policy(RetryPolicy::NextServer);
+        client.set_on_switch(Some(|old, new, reason| {
+            SWITCHES.fetch_add(1, Ordering::SeqCst);
+            match reason {
+                SwitchReason::Unreachable => {
+                    assert_eq!((old, new), (server(), IpAddress::v4(192, 168, 1, 2)))
+                }
+                SwitchReason::Blocked(BlockReason::Manual) => {
+                    assert_eq!((old, new), (IpAddress::v4(192, 168, 1, 2), server()));
+                    BLOCKED.fetch_add(1, Ordering::SeqCst);
+                }
+                SwitchReason::Blocked(_) => panic!("unexpected reason"),
+            }
+        }));
+
+        let mut now = Instant::from_secs(0);
+        let mut poll = |client: &mut Client| {
+            client.force_sync_override(now);
+            client.poll_requests(now, |_, _| Ok(())).unwrap();
+            now += Duration::from_secs(1);
+        };
+        poll(&mut client);
+        assert_eq!(SWITCHES.load(Ordering::SeqCst), 0);
+
+        // The request to the first server went unanswered
+        poll(&mut client);
+        assert_eq!(client.active_server(), Some(backup));
+        assert_eq!(SWITCHES.load(Ordering::SeqCst), 1);
+
+        client.block_server(backup).unwrap();
+        poll(&mut client);
+        assert_eq!(client.active_server(), Some(server()));
+        assert_eq!(SWITCHES.load(Ordering::SeqCst), 2);
+        assert_eq!(BLOCKED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_strict_validation() {
+        let (_, mut client) = client();
+        let receive = |client: &mut Client, bytes: &[u8]| {
+            expect_response(client);
+            client
+                .receive(bytes, server_endpoint(), Instant::from_secs(0))
+                .unwrap()
+        };
+
+        // The reference packet has a poll interval of 0
+        assert!(receive(&mut client, &PACKET_BYTES[..]).is_some());
+        client.set_strict_validation(true);
+        assert!(receive(&mut client, &PACKET_BYTES[..]).is_none());
+        assert_eq!(client.stats().invalid_poll, 1);
+
+        let mut bytes = PACKET_BYTES;
+        bytes[field::POLL] = 6;
+        assert!(receive(&mut client, &bytes[..]).is_some());
+
+        bytes[field::PRECISION] = 11;
+        assert!(receive(&mut client, &bytes[..]).is_none());
+        bytes[field::PRECISION] = -33i8 as u8;
+        assert!(receive(&mut client, &bytes[..]).is_none());
+        assert_eq!(client.stats().invalid_precision, 2);
+
+        // Primary server with an IP address as reference identifier
+        bytes[field::PRECISION] = -20i8 as u8;
+        bytes[field::STRATUM] = 1;
+        assert!(receive(&mut client, &bytes[..]).is_none());
+        assert_eq!(client.stats().invalid_reference, 1);
+        bytes[field::REFERENCE_IDENTIFIER].copy_from_slice(b"GPS\0");
+        assert!(receive(&mut client, &bytes[..]).is_some());
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        let (_, mut client) = client();
+        let receive = |client: &mut Client, bytes: &[u8]| {
+            expect_response(client);
+            client
+                .receive(bytes, server_endpoint(), Instant::from_secs(0))
+                .unwrap()
+        };
+
+        // Secondary server synchronized to itself
+        let mut bytes = PACKET_BYTES;
+        if let IpAddress::Ipv4(addr) = server() {
+            bytes[field::REFERENCE_IDENTIFIER].copy_from_slice(&addr.0);
+        }
+        assert!(receive(&mut client, &bytes[..]).is_none());
+        assert_eq!(client.stats().self_reference, 1);
+
+        // Primary servers carry a code instead
+        bytes[field::STRATUM] = 1;
+        assert!(receive(&mut client, &bytes[..]).is_some());
+
+        // Reference timestamp after the transmit timestamp
+        let mut bytes = PACKET_BYTES;
+        bytes[field::REFERENCE_TIMESTAMP].copy_from_slice(&PACKET_BYTES[field::TRANSMIT_TIMESTAMP]);
+        bytes[field::REFERENCE_TIMESTAMP][0] += 1;
+        assert!(receive(&mut client, &bytes[..]).is_none());
+        assert_eq!(client.stats().future_reference, 1);
+
+        // Servers that were never synchronized leave it zeroed
+        bytes[field::REFERENCE_TIMESTAMP].copy_from_slice(&[0; 8]);
+        assert!(receive(&mut client, &bytes[..]).is_some());
+
+        client.set_loop_detection(false);
+        bytes[field::REFERENCE_TIMESTAMP].copy_from_slice(&[0xff; 8]);
+        assert!(receive(&mut client, &bytes[..]).is_some());
+        assert_eq!(client.stats().future_reference, 1);
+    }
+
+    #[test]
+    fn test_require_auth() {
+        let (_, mut client) = client();
+        expect_response(&mut client);
+        let result = client
+            .receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .unwrap();
+        assert!(!result.is_authenticated());
+
+        client.set_require_auth(true);
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.stats().unauthenticated, 1);
+    }
+
+    #[test]
+    fn test_trusted_sources() {
+        let (_, mut client) = client();
+        let now = Instant::from_secs(0);
+        let other = IpAddress::v4(192, 168, 1, 2);
+        client.set_trusted_sources(&[other]).unwrap();
+
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&PACKET_BYTES[..], server_endpoint(), now),
+            Ok(None)
+        );
+        assert_eq!(client.stats().untrusted_source, 1);
+        assert_eq!(client.stats().wrong_source, 0);
+
+        // Broadcast packets are dropped as well
+        let mut bytes = PACKET_BYTES;
+        bytes[field::LI_VN_MODE] = 0x25; // NTPv4, broadcast mode
+        let broadcaster = IpEndpoint::new(IpAddress::v4(192, 168, 1, 50), SNTP_PORT);
+        assert_eq!(client.receive(&bytes[..], broadcaster, now), Ok(None));
+        assert_eq!(client.stats().untrusted_source, 2);
+
+        assert_eq!(
+            client.set_trusted_sources(&[other; MAX_SERVERS + 1]),
+            Err(SntpError::TooManyServers)
+        );
+        client.set_trusted_sources(&[other, server()]).unwrap();
+        let result = client.receive(&PACKET_BYTES[..], server_endpoint(), now);
+        assert!(result.unwrap().is_some());
+
+        client.set_trusted_sources(&[]).unwrap();
+        expect_response(&mut client);
+        let result = client.receive(&PACKET_BYTES[..], server_endpoint(), now);
+        assert!(result.unwrap().is_some());
+        assert_eq!(client.stats().untrusted_source, 2);
+    }
+
+    #[test]
+    fn test_unexpected_header() {
+        let (_, mut client) = client();
+        expect_response(&mut client);
+
+        let mut bytes = PACKET_BYTES;
+        bytes[field::LI_VN_MODE] = 0x25; // NTPv4, broadcast mode
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Err(SntpError::UnexpectedMode(ProtocolMode::Broadcast))
+        );
+
+        // Requests of other clients, from any source, are dropped
+        bytes[field::LI_VN_MODE] = 0x23; // NTPv4, client mode
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        let other = IpEndpoint::new(IpAddress::v4(192, 168, 1, 20), 40_000);
+        assert_eq!(
+            client.receive(&bytes[..], other, Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.stats().client_requests, 2);
+        assert_eq!(client.stats().wrong_source, 0);
+
+        bytes[field::LI_VN_MODE] = 0x2c; // NTPv5, server mode
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Err(SntpError::UnsupportedVersion(5))
+        );
+
+        bytes[field::LI_VN_MODE] = 0x24;
+        bytes[field::STRATUM] = 17;
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(
+            client.receive(&bytes[..47], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.stats().filtered, 2);
+
+        // Servers only implementing NTPv3 answer with their own version
+        let mut bytes = PACKET_BYTES;
+        bytes[field::LI_VN_MODE] = 0x1c; // NTPv3, server mode
+        let result = client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0));
+        assert!(result.unwrap().is_some());
+    }
+
+    #[test]
+    fn test_request_poll_interval() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(0);
+        assert_eq!(client.request_repr(now).poll_interval, DEFAULT_MINPOLL);
+
+        client.poll(&mut sockets, now).unwrap();
+        assert_eq!(client.request_repr(now).poll_interval, DEFAULT_MINPOLL + 1);
+
+        client.set_maxpoll(DEFAULT_MINPOLL).unwrap();
+        assert_eq!(client.request_repr(now).poll_interval, DEFAULT_MINPOLL);
+
+        // Once synchronized, the client polls at the maximum poll interval
+        let mut client: Client = Client::without_socket(server(), now);
+        client.poll_requests(now, |_, _| Ok(())).unwrap();
+        expect_response(&mut client);
+        client
+            .process_response(&PACKET_BYTES[..], server_endpoint(), now)
+            .unwrap()
+            .unwrap();
+        let later = client.next_request_at();
+        let mut request = [0; 48];
+        client
+            .poll_requests(later, |bytes, _| {
+                request.copy_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        let repr = Repr::parse_bytes(&request[..]).unwrap();
+        assert_eq!(repr.poll_interval, DEFAULT_MAXPOLL);
+    }
+
+    #[test]
+    fn test_request_leap_indicator() {
+        let (_, mut client) = client();
+        let now = Instant::from_secs(0);
+        let leap = |client: &Client| client.request_repr(now).leap_indicator;
+        assert_eq!(leap(&client), LeapIndicator::NoWarning);
+
+        assert_eq!(
+            client.set_leap_indicator(Some(LeapIndicator::AlarmCondition)),
+            Err(SntpError::OutOfRange)
+        );
+        client
+            .set_leap_indicator(Some(LeapIndicator::LastMinute61Sec))
+            .unwrap();
+        assert_eq!(leap(&client), LeapIndicator::AlarmCondition);
+        client.on_response_accepted();
+        assert_eq!(leap(&client), LeapIndicator::LastMinute61Sec);
+
+        client.set_leap_indicator(None).unwrap();
+        assert_eq!(leap(&client), LeapIndicator::NoWarning);
+    }
+
+    #[test]
+    fn test_effective_poll_interval() {
+        let (mut sockets, mut client) = client();
+        assert_eq!(
+            client.effective_poll_interval(),
+            poll_interval(DEFAULT_MINPOLL)
+        );
+
+        client.poll(&mut sockets, Instant::from_secs(0)).unwrap();
+        assert_eq!(
+            client.effective_poll_interval(),
+            poll_interval(DEFAULT_MINPOLL + 1)
+        );
+
+        client.server_poll = Some(10);
+        assert_eq!(client.effective_poll_interval(), poll_interval(10));
+
+        // The maximum poll interval is used once a response is accepted
+        let now = Instant::from_secs(0);
+        let mut client: Client = Client::without_socket(server(), now);
+        client.poll_requests(now, |_, _| Ok(())).unwrap();
+        expect_response(&mut client);
+        client
+            .process_response(&PACKET_BYTES[..], server_endpoint(), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            client.effective_poll_interval(),
+            poll_interval(DEFAULT_MAXPOLL)
+        );
+        assert_eq!(
+            client.next_request_at(),
+            now + poll_interval(DEFAULT_MAXPOLL)
+        );
+
+        // Until a request goes unanswered
+        let later = now + poll_interval(DEFAULT_MAXPOLL);
+        client.poll_requests(later, |_, _| Ok(())).unwrap();
+        assert_eq!(
+            client.effective_poll_interval(),
+            poll_interval(DEFAULT_MAXPOLL)
+        );
+        let later = client.next_request_at();
+        client.poll_requests(later, |_, _| Ok(())).unwrap();
+        assert_eq!(client.unanswered, 1);
+        assert!(client.effective_poll_interval() < poll_interval(DEFAULT_MAXPOLL));
+    }
+
+    #[test]
+    fn test_fast_then_lazy() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(0);
+        assert_eq!(
+            client.set_poll_schedule(PollSchedule::FastThenLazy { fast: 4, lazy: 10 }),
+            Err(SntpError::InvalidPollInterval)
+        );
+        assert_eq!(
+            client.set_poll_schedule(PollSchedule::FastThenLazy { fast: 10, lazy: 8 }),
+            Err(SntpError::InvalidPollInterval)
+        );
+        client
+            .set_poll_schedule(PollSchedule::FastThenLazy { fast: 6, lazy: 10 })
+            .unwrap();
+
+        // The fast interval doesn't back off
+        client.poll(&mut sockets, now).unwrap();
+        assert_eq!(client.next_request_at(), now + poll_interval(6));
+        assert_eq!(client.effective_poll_interval(), poll_interval(6));
+
+        expect_response(&mut client);
+        client
+            .receive(&PACKET_BYTES[..], server_endpoint(), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.effective_poll_interval(), poll_interval(10));
+    }
+
+    #[test]
+    fn test_auto_bind() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(0);
+        client.set_auto_bind(false);
+        assert_eq!(client.poll(&mut sockets, now), Err(SntpError::NotBound));
+
+        sockets
+            .get::<UdpSocket>(client.udp_handle.unwrap())
+            .bind(IpEndpoint::new(IpAddress::v4(192, 168, 1, 100), 1234))
+            .unwrap();
+        assert_eq!(client.poll(&mut sockets, now), Ok(None));
+        assert_eq!(
+            sockets
+                .get::<UdpSocket>(client.udp_handle.unwrap())
+                .endpoint()
+                .port,
+            1234
+        );
+    }
+
+    #[test]
+    fn test_poll_with_deadline() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(1_000);
+        assert_eq!(
+            client.poll_with_deadline(&mut sockets, now),
+            (Ok(None), now + poll_interval(DEFAULT_MINPOLL))
+        );
+    }
+
+    #[test]
+    fn test_force_sync_rate_limit() {
+        let (_, mut client) = client();
+        let now = Instant::from_secs(1_000);
+        assert_eq!(client.next_poll(now), Duration::from_millis(0));
+
+        client.last_request = Some(now);
+        client.next_request = now + Duration::from_secs(3_600);
+        client.force_sync(now + Duration::from_secs(10));
+        assert_eq!(
+            client.next_poll(now + Duration::from_secs(10)),
+            poll_interval(DEFAULT_MINPOLL) - Duration::from_secs(10)
+        );
+
+        client.server_poll = Some(10);
+        assert_eq!(client.next_poll(now), poll_interval(10));
+    }
+
+    #[test]
+    fn test_invalid_address() {
+        let broadcast = IpAddress::v4(255, 255, 255, 255);
+        let mut client: Client = Client::without_socket(broadcast, Instant::from_secs(0));
+        assert_eq!(
+            client.block_reason(broadcast),
+            Some(BlockReason::InvalidAddress)
+        );
+        let result = client.poll_requests(Instant::from_secs(0), |_, _| Ok(()));
+        assert_eq!(result, Err(SntpError::NoServersAvailable));
+
+        let multicast = IpAddress::v4(224, 0, 1, 1);
+        assert_eq!(client.add_server(multicast), Err(SntpError::InvalidAddress));
+        assert_eq!(
+            client.add_server(IpAddress::v4(0, 0, 0, 0)),
+            Err(SntpError::InvalidAddress)
+        );
+        assert_eq!(client.add_server(IpAddress::v4(127, 0, 0, 1)), Ok(()));
+        client.block_server(IpAddress::v4(127, 0, 0, 1)).unwrap();
+
+        // Unblocking overrides the check, even across resets
+        client.unblock_server(multicast).unwrap();
+        assert_eq!(client.add_server(multicast), Ok(()));
+        client.reset(Instant::from_secs(0));
+        assert_eq!(
+            client.block_reason(broadcast),
+            Some(BlockReason::InvalidAddress)
+        );
+        assert_eq!(client.block_reason(multicast), None);
+        assert_eq!(client.servers.select(FAILOVER_THRESHOLD), Some(multicast));
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let (_, mut client) = client();
+        let backup = IpAddress::v4(192, 168, 1, 2);
+        client.add_server(backup).unwrap();
+        assert_eq!(client.active_server(), Some(server()));
+
+        let mut bytes = PACKET_BYTES;
+        bytes[field::STRATUM] = 0;
+        bytes[field::REFERENCE_IDENTIFIER].copy_from_slice(b"DENY");
+        expect_response(&mut client);
+        client
+            .receive(&bytes[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap();
+        assert_eq!(
+            client.block_reason(server()),
+            Some(BlockReason::KissOfDeath(*b"DENY"))
+        );
+        assert_eq!(client.active_server(), None);
+        assert_eq!(client.servers.select(FAILOVER_THRESHOLD), Some(backup));
+
+        client.block_server(backup).unwrap();
+        assert_eq!(client.block_reason(backup), Some(BlockReason::Manual));
+        assert_eq!(client.servers.select(FAILOVER_THRESHOLD), None);
+
+        let result = client.poll_requests(Instant::from_secs(0), |_, _| Ok(()));
+        assert_eq!(result, Err(SntpError::NoServersAvailable));
+        assert_eq!(client.last_request, None);
+
+        client.unblock_server(server()).unwrap();
+        assert_eq!(client.servers.select(FAILOVER_THRESHOLD), Some(server()));
+        let result = client.poll_requests(Instant::from_secs(0), |_, _| Ok(()));
+        assert_eq!(result, Ok(None));
+        assert_eq!(client.last_request, Some(Instant::from_secs(0)));
+        assert_eq!(
+            client.block_server(IpAddress::v4(10, 0, 0, 1)),
+            Err(SntpError::UnknownServer)
+        );
+    }
+
+    #[test]
+    fn test_response_matching() {
+        let (_, mut client) = client();
+        let now = Instant::from_secs(0);
+
+        // Unsolicited response
+        let recv = |client: &mut Client, endpoint| client.receive(&PACKET_BYTES[..], endpoint, now);
+        assert_eq!(recv(&mut client, server_endpoint()), Ok(None));
+        assert_eq!(client.stats().wrong_source, 1);
+
+        // Response from another server
+        expect_response(&mut client);
+        let other = IpEndpoint::new(IpAddress::v4(192, 168, 1, 2), SNTP_PORT);
+        assert_eq!(recv(&mut client, other), Ok(None));
+        assert_eq!(client.stats().wrong_source, 2);
+
+        // Response to another request
+        client.servers.get_mut(server()).unwrap().request_timestamp =
+            Some(Timestamp { sec: 1, frac: 0 });
+        assert_eq!(recv(&mut client, server_endpoint()), Ok(None));
+        assert_eq!(client.stats().wrong_origin, 1);
+
+        // Duplicate response
+        expect_response(&mut client);
+        assert!(recv(&mut client, server_endpoint()).unwrap().is_some());
+        assert_eq!(recv(&mut client, server_endpoint()), Ok(None));
+        assert_eq!(client.stats().wrong_origin, 2);
+
+        // Only the significant bits of the originate timestamp are compared
+        let mut bytes = PACKET_BYTES;
+        bytes[field::ORIGINATE_TIMESTAMP][7] = 0xff;
+        expect_response(&mut client);
+        assert!(client
+            .receive(&bytes[..], server_endpoint(), now)
+            .unwrap()
+            .is_some());
+        bytes[field::ORIGINATE_TIMESTAMP][6] = 0x10;
+        expect_response(&mut client);
+        assert_eq!(client.receive(&bytes[..], server_endpoint(), now), Ok(None));
+        assert_eq!(client.stats().wrong_origin, 3);
+    }
+
+    #[test]
+    fn test_sequence_tagging() {
+        let mut client: Client = Client::without_socket(server(), Instant::from_secs(0));
+        client.set_sequence_tagging(true);
+        assert_eq!(client.last_sequence(), Some(0));
+
+        let mut now = Instant::from_secs(0);
+        let mut tags = [0; 3];
+        for tag in tags.iter_mut() {
+            client.force_sync_override(now);
+            client
+                .poll_requests(now, |bytes, _| {
+                    *tag = Repr::parse_bytes(bytes).unwrap().xmit_timestamp.frac & 0xff;
+                    Ok(())
+                })
+                .unwrap();
+            now += Duration::from_secs(1);
+        }
+        assert_eq!(tags, [1, 2, 3]);
+        assert_eq!(client.last_sequence(), Some(3));
+
+        // The sequence number must be echoed exactly
+        let mut bytes = reply_from(&client, server(), 1_589_753_489);
+        bytes[field::ORIGINATE_TIMESTAMP][7] ^= 0x01;
+        let result = client.process_response(&bytes[..], server_endpoint(), now);
+        assert_eq!(result, Ok(None));
+        assert_eq!(client.stats().wrong_origin, 1);
+        bytes[field::ORIGINATE_TIMESTAMP][7] ^= 0x01;
+        let result = client.process_response(&bytes[..], server_endpoint(), now);
+        assert!(result.unwrap().is_some());
+
+        client.set_sequence_tagging(false);
+        assert_eq!(client.last_sequence(), None);
+    }
+
+    #[test]
+    fn test_clock() {
+        use core::cell::Cell;
+
+        /// Clock reading a time set by the test.
+        struct FakeClock<'a>(&'a Cell<i64>);
+
+        impl Clock for FakeClock<'_> {
+            fn now(&self) -> Instant {
+                Instant::from_millis(self.0.get())
+            }
+        }
+
+        let (mut sockets, mut client) = client();
+        assert_eq!(client.poll_now(&mut sockets), Err(SntpError::NoClock));
+
+        let time = Cell::new(5_000);
+        let mut sockets = SocketSet::new(leak((0..1).map(|_| None).collect()));
+        let mut client = Client::<4, FakeClock>::new_sized(
+            &mut sockets,
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            server(),
+            Instant::from_secs(0),
+        );
+        client.set_clock(Some(FakeClock(&time)));
+        assert_eq!(client.poll_now(&mut sockets), Ok(None));
+        assert_eq!(client.last_request, Some(Instant::from_millis(5_000)));
+
+        time.set(6_000);
+        assert_eq!(
+            client.next_poll(Instant::from_millis(time.get())),
+            poll_interval(DEFAULT_MINPOLL) - Duration::from_secs(1)
+        );
+
+        // Any function returning the time is a clock
+        let mut client = Client::<4, _>::without_socket(server(), Instant::from_secs(0));
+        client.set_clock(Some(|| Instant::from_millis(time.get())));
+        assert_eq!(client.poll_now(&mut sockets), Err(SntpError::InvalidSocket));
+    }
+
+    #[test]
+    fn test_link_down() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(1_000);
+
+        client.set_link_up(false, now);
+        client.poll(&mut sockets, now).unwrap();
+        assert_eq!(client.last_request, None);
+        assert!(!client.outstanding);
+
+        let later = now + Duration::from_secs(3_600);
+        client.set_link_up(true, later);
+        client.poll(&mut sockets, later).unwrap();
+        assert!(client.outstanding);
+        client.set_link_up(false, later);
+        assert!(!client.outstanding);
+        assert_eq!(client.unanswered, 0);
+
+        client.set_link_up(true, later);
+        assert_eq!(client.next_poll(later), poll_interval(DEFAULT_MINPOLL));
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(0);
+        client.poll(&mut sockets, now).unwrap();
+        assert!(client.outstanding);
+        let bytes = reply_from(&client, server(), 1_589_753_489);
+        let next_request = client.next_request_at();
+
+        client.cancel_request();
+        assert!(!client.outstanding);
+        assert_eq!(client.next_request_at(), next_request);
+        assert_eq!(client.receive(&bytes[..], server_endpoint(), now), Ok(None));
+        assert_eq!(client.stats().wrong_source, 1);
+        assert_eq!(client.samples().count(), 0);
+
+        client.account_unanswered();
+        assert_eq!(client.unanswered, 0);
+    }
+
+    #[test]
+    fn test_quality_key() {
+        let result = |delay, max_error| SyncResult {
+            timestamp: 0,
+            unix_seconds: 0,
+            server_timestamp: Timestamp { sec: 0, frac: 0 },
+            processing_delay: 0,
+            server_sync_age: None,
+            offset: 0,
+            delay,
+            root_delay: delay,
+            max_error,
+            unsynchronized: false,
+            provisional: false,
+            stratum: 2,
+            authenticated: false,
+            server: server(),
+        };
+        let mut results = [
+            result(20_000, 15_000),
+            result(10_000, 30_000),
+            result(20_000, 12_000),
+        ];
+        assert_eq!(
+            results.iter().min_by_key(|r| r.quality_key()),
+            Some(&result(10_000, 30_000))
+        );
+        results.sort_unstable_by_key(SyncResult::quality_key);
+        assert_eq!(
+            results,
+            [
+                result(10_000, 30_000),
+                result(20_000, 12_000),
+                result(20_000, 15_000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sync_result_record() {
+        let result = SyncResult {
+            timestamp: 1_589_753_489,
+            unix_seconds: 1_589_753_489,
+            server_timestamp: Timestamp {
+                sec: 3_798_742_289,
+                frac: 0x8000_0000,
+            },
+            processing_delay: 16,
+            server_sync_age: Some(3_600_000_000),
+            offset: -1_234_567,
+            delay: 42_000,
+            root_delay: 50_000,
+            max_error: 25_000,
+            unsynchronized: true,
+            provisional: true,
+            stratum: 3,
+            authenticated: true,
+            server: server(),
+        };
+        assert_eq!(result.offset_micros(), -1_234_567);
+        assert_eq!(result.offset(), Duration::from_millis(1_234));
+        assert!(result.offset_is_negative());
+        assert_eq!(result.delay_micros(), 42_000);
+
+        let mut buf = [0xa5; 72];
+        assert_eq!(result.encode(&mut buf), Ok(SYNC_RESULT_RECORD_LEN));
+        assert_eq!(&buf[..8], &[2, 0x8f, 0x80, 0, 0x5e, 0xc1, 0xb6, 0x91]);
+        assert_eq!(&buf[64..], &[0xa5; 8]);
+        // The server address isn't recorded
+        let result = SyncResult {
+            server: IpAddress::Unspecified,
+            ..result
+        };
+        assert_eq!(SyncResult::decode(&buf), Ok(result));
+
+        let never_synced = SyncResult {
+            server_sync_age: None,
+            ..result
+        };
+        never_synced.encode(&mut buf).unwrap();
+        assert_eq!(&buf[56..64], &[0xff; 8]);
+        assert_eq!(SyncResult::decode(&buf), Ok(never_synced));
+
+        assert_eq!(
+            result.encode(&mut buf[..63]),
+            Err(SntpError::BufferTooSmall)
+        );
+        assert_eq!(
+            SyncResult::decode(&buf[..63]),
+            Err(SntpError::BufferTooSmall)
+        );
+        buf[0] = 1;
+        assert_eq!(
+            SyncResult::decode(&buf),
+            Err(SntpError::UnsupportedVersion(1))
+        );
+    }
+
+    #[test]
+    fn test_receive_padded() {
+        let (_, mut client) = client();
+        let mut bytes = [0; 60];
+        bytes[..48].copy_from_slice(&PACKET_BYTES);
+        expect_response(&mut client);
+        let result = client
+            .receive(&bytes[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.timestamp, 1589753489);
+
+        client.set_max_packet_len(59);
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Err(SntpError::PacketTooLarge)
+        );
+        assert_eq!(client.stats().oversized, 1);
+        assert_eq!(client.stats().invalid_header, 0);
+        assert!(client
+            .receive(&bytes[..59], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_network_backoff() {
+        let (mut sockets, mut client) = client();
+        client.servers = ServerList::new(IpAddress::Unspecified);
+        client.unblock_server(IpAddress::Unspecified).unwrap();
+        let now = Instant::from_secs(1_000);
+
+        assert_eq!(
+            client.poll(&mut sockets, now),
+            Err(SntpError::Network(Error::Unaddressable))
+        );
+        let backoff = client.network_backoff().unwrap();
+        assert!(backoff >= Duration::from_millis(500) && backoff <= Duration::from_secs(1));
+        assert_eq!(client.next_poll(now), backoff);
+        assert_eq!(client.poll(&mut sockets, now), Ok(None));
+
+        let now = now + backoff;
+        assert!(client.poll(&mut sockets, now).is_err());
+        let backoff = client.network_backoff().unwrap();
+        assert!(backoff >= Duration::from_secs(1) && backoff <= Duration::from_secs(2));
+
+        client.servers = ServerList::new(server());
+        assert_eq!(client.poll(&mut sockets, now + backoff), Ok(None));
+        assert_eq!(client.network_backoff(), None);
+    }
+
+    #[test]
+    fn test_seed_offset() {
+        let (_, mut client) = client();
+        client.seed_offset(1_589_753_000_000_000, Instant::from_secs(10));
+        assert_eq!(client.state(), SyncState::Holdover);
+        assert_eq!(
+            client.current_time(Instant::from_secs(20)),
+            Some(Timestamp::from_unix_micros(1_589_753_020_000_000))
+        );
+
+        // The local clock is 2.5 s ahead
+        let local = Instant::from_secs(1_589_753_010);
+        client.seed_offset(-2_500_000, local);
+        assert_eq!(client.estimated_offset_micros(local), Some(-2_500_000));
+        assert_eq!(
+            client.current_time(local + Duration::from_secs(10)),
+            Some(Timestamp::from_unix_micros(1_589_753_017_500_000))
+        );
+
+        expect_response(&mut client);
+        client
+            .receive(&PACKET_BYTES[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.samples().count(), 1);
+        assert_eq!(client.drift_ppb(), None);
+    }
+
+    #[test]
+    fn test_suggested_step() {
+        let (_, mut client) = client();
+        assert_eq!(client.suggested_step(Instant::from_secs(0)), None);
+
+        client.filter.push(Sample {
+            offset: -100_000,
+            delay: 0,
+            received_at: Instant::from_secs(0),
+        });
+        assert_eq!(
+            client.estimated_offset_micros(Instant::from_secs(1)),
+            Some(-100_000)
+        );
+        assert_eq!(client.suggested_step(Instant::from_secs(1)), None);
+
+        client.set_step_threshold(Duration::from_millis(50));
+        assert_eq!(
+            client.suggested_step(Instant::from_secs(1)),
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn test_unsynchronized() {
+        let (_, mut client) = client();
+        let mut bytes = PACKET_BYTES;
+        bytes[0] |= 0xc0;
+
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.stats().unsynchronized, 1);
+
+        client.set_accept_unsynchronized(true);
+        let result = client
+            .receive(&bytes[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .unwrap();
+        assert!(result.is_unsynchronized());
+    }
+
+    #[test]
+    fn test_external_step() {
+        let (_, mut client) = client();
+        let receive = |client: &mut Client, secs, server_secs| {
+            expect_response(client);
+            let bytes = reply_from(client, server(), server_secs);
+            client
+                .receive(&bytes[..], server_endpoint(), Instant::from_secs(secs))
+                .unwrap()
+                .unwrap();
+        };
+
+        receive(&mut client, 0, 1_000);
+        receive(&mut client, 64, 1_064);
+        assert_eq!(client.detected_external_step(), None);
+
+        // The local clock was set back by 1000 s
+        receive(&mut client, 128, 2_128);
+        assert_eq!(
+            client.detected_external_step(),
+            Some(Duration::from_secs(1_000))
+        );
+        assert_eq!(client.samples().count(), 1);
+
+        receive(&mut client, 192, 2_192);
+        assert_eq!(client.detected_external_step(), None);
+    }
+
+    #[test]
+    fn test_set_next_request() {
+        let (_, mut client) = client();
+        assert_eq!(client.next_request(), Instant::from_secs(0));
+
+        client.set_next_request(Instant::from_secs(100));
+        assert_eq!(client.next_request(), Instant::from_secs(100));
+        assert_eq!(
+            client.next_poll(Instant::from_secs(40)),
+            Duration::from_secs(60)
+        );
+
+        // Requests can't be scheduled closer than the minimum poll interval
+        client.last_request = Some(Instant::from_secs(100));
+        client.set_next_request(Instant::from_secs(101));
+        assert_eq!(
+            client.next_request(),
+            Instant::from_secs(100) + client.min_spacing()
+        );
+
+        client.force_sync_override(Instant::from_secs(101));
+        assert_eq!(client.next_request(), Instant::from_secs(101));
+    }
+
+    #[test]
+    fn test_primary_step_threshold() {
+        let receive = |client: &mut Client, secs, server_secs, stratum| {
+            expect_response(client);
+            let mut bytes = reply_from(client, server(), server_secs);
+            bytes[1] = stratum;
+            let result = client
+                .receive(&bytes[..], server_endpoint(), Instant::from_secs(secs))
+                .unwrap()
+                .unwrap();
+            assert_eq!(result.stratum(), stratum);
+        };
+
+        for &stratum in &[1, 2] {
+            let (_, mut client) = client();
+            client.set_step_threshold(Duration::from_secs(10));
+            client.set_primary_step_threshold(Some(Duration::from_millis(100)));
+
+            // Keep the round trip short, for a small maximum error
+            receive(&mut client, 0, 1_000, stratum);
+            receive(&mut client, 0, 1_000, stratum);
+            // The local clock was set back by 1 s
+            receive(&mut client, 0, 1_001, stratum);
+            let expected = if stratum == 1 {
+                Some(Duration::from_secs(1))
+            } else {
+                None
+            };
+            assert_eq!(client.detected_external_step(), expected);
+        }
+    }
+
+    #[test]
+    fn test_without_socket() {
+        let mut client = Client::without_socket(server(), Instant::from_secs(0));
+        let now = Instant::from_secs(0);
+        let mut sockets = SocketSet::new(leak((0..1).map(|_| None).collect()));
+        assert_eq!(
+            client.poll(&mut sockets, now),
+            Err(SntpError::InvalidSocket)
+        );
+
+        let mut sent = None;
+        let result = client.poll_requests(now, |bytes, endpoint| {
+            // No extension fields nor MAC
+            assert_eq!(bytes.len(), MIN_BUFFER_LEN);
+            sent = Some((Repr::parse_bytes(bytes).unwrap(), endpoint));
+            Ok(())
+        });
+        assert_eq!(result, Ok(None));
+        let (request, endpoint) = sent.unwrap();
+        assert_eq!(endpoint, server_endpoint());
+        assert_eq!(request.protocol_mode, ProtocolMode::Client);
+        assert_eq!(client.state(), SyncState::Syncing);
+
+        // Nothing is due until the next poll interval
+        let result = client.poll_requests(now, |_, _| panic!("unexpected request"));
+        assert_eq!(result, Ok(None));
+
+        let bytes = reply_from(&client, server(), 1_589_753_489);
+        let result = client.process_response(&bytes[..], server_endpoint(), now);
+        assert!(result.unwrap().is_some());
+        assert_eq!(client.state(), SyncState::Synced);
+        assert_eq!(client.next_poll(now), poll_interval(DEFAULT_MAXPOLL));
+    }
+
+    #[test]
+    fn test_last_raw_request() {
+        let mut client: Client = Client::without_socket(server(), Instant::from_secs(0));
+        assert_eq!(client.last_raw_request(), None);
+
+        let mut sent = [0; 48];
+        client
+            .poll_requests(Instant::from_secs(0), |bytes, _| {
+                sent.copy_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        let mut golden = [0; 48];
+        // NTPv4 client, poll 2^6 s, precision 2^-10 s
+        golden[..4].copy_from_slice(&[0x23, 0x00, 0x06, 0xf6]);
+        // Transmit timestamp: Jan 1, 1970
+        golden[40..44].copy_from_slice(&[0x83, 0xaa, 0x7e, 0x80]);
+        assert_eq!(client.last_raw_request(), Some(&golden[..]));
+        assert_eq!(sent, golden);
+
+        client.reset(Instant::from_secs(0));
+        assert_eq!(client.last_raw_request(), None);
+    }
+
+    #[test]
+    fn test_acceptance_window() {
+        let (_, mut client) = client();
+        client.set_acceptance_window(Some(Duration::from_secs(1_000)));
+        let receive = |client: &mut Client, secs, server_secs| {
+            expect_response(client);
+            let bytes = reply_from(client, server(), server_secs);
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(secs))
+        };
+
+        // Any time is accepted on cold start
+        assert!(receive(&mut client, 0, 1_000_000).unwrap().is_some());
+        assert!(receive(&mut client, 64, 1_000_064 + 999).unwrap().is_some());
+        assert_eq!(receive(&mut client, 128, 1_000_128 - 2_000), Ok(None));
+        assert_eq!(client.stats().outside_window, 1);
+    }
+
+    /// Runs a complete exchange with a server whose clock is 5 s ahead, with
+    /// the given one-way delays and processing time, in milliseconds.
+    fn round_trip(outbound: i64, processing: i64, inbound: i64) -> SyncResult {
+        let mut sockets_entries: [_; 1] = Default::default();
+        let mut sockets = SocketSet::new(&mut sockets_entries[..]);
+        let (mut rx_metadata, mut rx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let (mut tx_metadata, mut tx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let sntp_server = Server::new(
+            &mut sockets,
+            UdpSocketBuffer::new(&mut rx_metadata[..], &mut rx_storage[..]),
+            UdpSocketBuffer::new(&mut tx_metadata[..], &mut tx_storage[..]),
+        );
+        let server_time = |local: Instant| Timestamp::from_unix_micros(micros(local) + 5_000_000);
+
+        // T1
+        let mut client: Client = Client::without_socket(server(), Instant::from_secs(0));
+        let sent_at = Instant::from_secs(1_000);
+        let mut request = [0; 48];
+        client
+            .poll_requests(sent_at, |bytes, _| {
+                request.copy_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+
+        // T2 and T3
+        let received_by_server = sent_at + Duration::from_millis(outbound as u64);
+        let mut reply = sntp_server
+            .reply_to(&request[..], server_time(received_by_server))
+            .unwrap();
+        let sent_by_server = received_by_server + Duration::from_millis(processing as u64);
+        reply.xmit_timestamp = server_time(sent_by_server);
+        let mut bytes = [0; 48];
+        reply.emit_to_bytes(&mut bytes[..]).unwrap();
+
+        // T4
+        let received_at = sent_by_server + Duration::from_millis(inbound as u64);
+        client
+            .process_response(&bytes[..], server_endpoint(), received_at)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Converting timestamps to microseconds rounds them down by up to 1 us
+        let assert_near = |value: i64, expected: i64| {
+            assert!((value - expected).abs() <= 1, "{} != {}", value, expected)
+        };
+
+        // offset = ((T2 - T1) + (T3 - T4)) / 2, delay = (T4 - T1) - (T3 - T2)
+        let result = round_trip(20, 2, 20);
+        assert_near(result.offset_micros(), 5_000_000);
+        assert_near(result.delay_micros(), 40_000);
+        assert_near(result.processing_delay_micros(), 2_000);
+
+        // Asymmetric delays bias the offset by half their difference
+        let result = round_trip(10, 2, 30);
+        assert_near(result.offset_micros(), 5_000_000 - 10_000);
+        assert_near(result.delay_micros(), 40_000);
+
+        let result = round_trip(45, 0, 5);
+        assert_near(result.offset_micros(), 5_000_000 + 20_000);
+        assert_near(result.delay_micros(), 50_000);
+    }
+
+    #[test]
+    fn test_sample_selection() {
+        let (_, mut client) = client();
+        client.set_sample_selection(SampleSelection::Filtered);
+        let receive = |client: &mut Client, sent_at, secs, server_secs| {
+            expect_response(client);
+            client.servers.get_mut(server()).unwrap().sent_at = Some(Instant::from_millis(sent_at));
+            let bytes = reply_from(client, server(), server_secs);
+            client
+                .receive(&bytes[..], server_endpoint(), Instant::from_secs(secs))
+                .unwrap()
+                .unwrap()
+        };
+
+        let first = receive(&mut client, 63_990, 64, 1_064);
+        assert_eq!(first.delay_micros(), 10_000);
+        let second = receive(&mut client, 127_900, 128, 1_128);
+        assert_eq!(second.delay_micros(), 10_000);
+        assert_eq!(second.offset_micros(), first.offset_micros());
+
+        client.set_sample_selection(SampleSelection::Latest);
+        let third = receive(&mut client, 191_900, 192, 1_192);
+        assert_eq!(third.delay_micros(), 100_000);
+        assert_eq!(third.offset_micros(), 1_000_050_000);
+    }
+
+    #[test]
+    fn test_server_sync_age() {
+        let (_, mut client) = client();
+        let receive = |client: &mut Client, bytes: &[u8]| {
+            expect_response(client);
+            client
+                .receive(bytes, server_endpoint(), Instant::from_secs(0))
+                .unwrap()
+        };
+
+        // The reference packet was synchronized 544.36 s before transmission
+        let result = receive(&mut client, &PACKET_BYTES[..]).unwrap();
+        assert_eq!(
+            result.server_sync_age(),
+            Some(Duration::from_millis(544_358))
+        );
+
+        let mut never_synced = PACKET_BYTES;
+        never_synced[field::REFERENCE_TIMESTAMP].copy_from_slice(&[0; 8]);
+        let result = receive(&mut client, &never_synced[..]).unwrap();
+        assert_eq!(result.server_sync_age(), None);
+
+        client.set_max_server_sync_age(Some(Duration::from_secs(600)));
+        assert!(receive(&mut client, &PACKET_BYTES[..]).is_some());
+        assert!(receive(&mut client, &never_synced[..]).is_none());
+        client.set_max_server_sync_age(Some(Duration::from_secs(300)));
+        assert!(receive(&mut client, &PACKET_BYTES[..]).is_none());
+        assert_eq!(client.stats().stale_server, 2);
+    }
+
+    #[test]
+    fn test_clock_mapping() {
+        let (_, mut client) = client();
+        let receive = |client: &mut Client, secs, server_secs| {
+            expect_response(client);
+            client.servers.get_mut(server()).unwrap().sent_at = Some(Instant::from_secs(secs));
+            let bytes = reply_from(client, server(), server_secs);
+            client
+                .receive(&bytes[..], server_endpoint(), Instant::from_secs(secs))
+                .unwrap()
+                .unwrap();
+        };
+        assert_eq!(client.clock_mapping(), None);
+
+        // The local clock runs 1000 ppm slow, without being stepped
+        client.set_step_threshold(Duration::from_secs(10));
+        receive(&mut client, 1_000, 11_000);
+        receive(&mut client, 2_000, 12_001);
+        let mapping = client.clock_mapping().unwrap();
+        assert_eq!(
+            mapping,
+            ClockMapping {
+                instant: Instant::from_secs(2_000),
+                unix_micros: 12_001_000_000,
+                drift_ppb: 1_000_000,
+            }
+        );
+
+        let now = Instant::from_secs(3_000);
+        let wall = client.corrected_time(now).unwrap();
+        assert_eq!(wall, mapping.to_wall(now));
+        assert_eq!(wall.to_unix_micros(), 13_002_000_000);
+        assert_eq!(mapping.to_instant(wall), Some(now));
+    }
+
+    #[test]
+    fn test_asymmetry() {
+        let (_, mut client) = client();
+        let receive = |client: &mut Client, sent_at, secs, server_secs| {
+            expect_response(client);
+            client.servers.get_mut(server()).unwrap().sent_at = Some(Instant::from_millis(sent_at));
+            let bytes = reply_from(client, server(), server_secs);
+            client
+                .receive(&bytes[..], server_endpoint(), Instant::from_secs(secs))
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(client.min_delay_micros(), None);
+
+        receive(&mut client, 63_990, 64, 1_064);
+        let result = receive(&mut client, 127_900, 128, 1_128);
+        assert_eq!(client.min_delay_micros(), Some(10_000));
+
+        // The request spent 90 ms in a queue: the offset is biased by 45 ms
+        assert_eq!(result.offset_micros(), 1_000_050_000);
+        assert_eq!(result.offset_asymmetry_corrected(90_000), 1_000_005_000);
+        assert_eq!(result.offset_asymmetry_corrected(-90_000), 1_000_095_000);
+        assert_eq!(result.offset_asymmetry_corrected(0), 1_000_050_000);
+
+        client.seed_offset(1_000_000_000, Instant::from_secs(128));
+        assert_eq!(client.min_delay_micros(), None);
+    }
+
+    #[test]
+    fn test_min_samples() {
+        let (_, mut client) = client();
+        client.set_min_samples(3);
+        let now = Instant::from_secs(0);
+        let receive = |client: &mut Client| {
+            client.on_request_sent();
+            expect_response(client);
+            client
+                .process_response(&PACKET_BYTES[..], server_endpoint(), now)
+                .unwrap()
+                .unwrap()
+        };
+
+        assert_eq!(client.corrected_time(now), None);
+        let result = receive(&mut client);
+        assert!(result.is_provisional());
+        assert_eq!(client.state(), SyncState::Syncing);
+        assert_eq!(client.current_time(now), None);
+        assert_eq!(
+            client.corrected_time(now),
+            Some(Timestamp::from_unix_micros(result.offset_micros()))
+        );
+        assert!(receive(&mut client).is_provisional());
+        assert_eq!(client.state(), SyncState::Syncing);
+        assert!(!receive(&mut client).is_provisional());
+        assert_eq!(client.current_time(now), client.corrected_time(now));
+        assert_eq!(client.state(), SyncState::Synced);
+        assert!(!receive(&mut client).is_provisional());
+
+        client.reset(now);
+        assert!(receive(&mut client).is_provisional());
+
+        client.set_min_samples(u8::MAX);
+        assert_eq!(client.min_samples, DEFAULT_SAMPLE_COUNT as u8);
+    }
+
+    #[test]
+    fn test_reset() {
+        let (mut sockets, mut client) = client();
+        let now = Instant::from_secs(0);
+        client.set_maxpoll(10).unwrap();
+        client.poll(&mut sockets, now).unwrap();
+        expect_response(&mut client);
+        client
+            .receive(&PACKET_BYTES[..], server_endpoint(), now)
+            .unwrap()
+            .unwrap();
+        client.on_response_accepted();
+        client.servers.get_mut(server()).unwrap().failures = 2;
+        assert_eq!(client.state(), SyncState::Synced);
+
+        let later = now + Duration::from_secs(10);
+        client.reset(later);
+        assert_eq!(client.state(), SyncState::Unsynced);
+        assert_eq!(client.samples().count(), 0);
+        assert_eq!(client.last_repr(), None);
+        assert_eq!(client.time_since_last_sync(later), None);
+        assert_eq!(client.servers.get(server()).unwrap().failures, 0);
+        assert_eq!(client.servers.get(server()).unwrap().previous, None);
+        assert_eq!(client.next_poll(later), Duration::from_millis(0));
+        assert_eq!(
+            client.effective_poll_interval(),
+            poll_interval(DEFAULT_MINPOLL)
+        );
+        assert_eq!(client.maxpoll, 10);
+    }
+
+    #[test]
+    fn test_allowed_leap() {
+        let (_, mut client) = client();
+        let mut bytes = PACKET_BYTES;
+        bytes[0] = (bytes[0] & !0xc0) | 0x40;
+
+        client.set_allowed_leap(&[LeapIndicator::NoWarning]);
+        expect_response(&mut client);
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.stats().disallowed_leap, 1);
+        bytes[0] |= 0xc0;
+        assert_eq!(
+            client.receive(&bytes[..], server_endpoint(), Instant::from_secs(0)),
+            Ok(None)
+        );
+        assert_eq!(client.stats().unsynchronized, 1);
+
+        client.set_allowed_leap(&[LeapIndicator::NoWarning, LeapIndicator::AlarmCondition]);
+        assert!(client
+            .receive(&bytes[..], server_endpoint(), Instant::from_secs(0))
+            .unwrap()
+            .is_some());
+    }
+
+    /// Builds a reply to the request last sent to `server`, with the server
+    /// clock `secs` seconds ahead of the Unix epoch.
+    fn reply_from(client: &Client, server: IpAddress, secs: i64) -> [u8; 48] {
+        let mut repr = Repr::parse_bytes(&PACKET_BYTES[..]).unwrap();
+        repr.orig_timestamp = client
+            .servers
+            .get(server)
+            .unwrap()
+            .request_timestamp
+            .unwrap();
+        repr.recv_timestamp = Timestamp::from_unix_micros(secs * 1_000_000);
+        repr.xmit_timestamp = repr.recv_timestamp;
+        repr.ref_timestamp = repr.recv_timestamp;
+        let mut bytes = [0; 48];
+        repr.emit_to_bytes(&mut bytes[..]).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let (mut sockets, _) = client();
+        let handle = sockets.add(UdpSocket::new(
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 1]), leak(vec![0; 128])),
+            UdpSocketBuffer::new(leak(vec![UdpPacketMetadata::EMPTY; 4]), leak(vec![0; 256])),
+        ));
+        let mut client =
+            Client::with_socket(&sockets, handle, server(), Instant::from_secs(0)).unwrap();
+        let (b, c) = (IpAddress::v4(192, 168, 1, 2), IpAddress::v4(192, 168, 1, 3));
+        client.add_server(b).unwrap();
+        client.add_server(c).unwrap();
+        client.set_query_mode(QueryMode::Concurrent);
+
+        let now = Instant::from_secs(0);
+        assert_eq!(client.poll(&mut sockets, now), Ok(None));
+        assert!(client.servers.iter().all(|entry| entry.is_pending()));
+        assert_eq!(client.next_poll(now), COLLECT_WINDOW);
+
+        // All servers replied: the median offset is selected
+        for &(addr, secs) in &[(server(), 300), (b, 100)] {
+            let bytes = reply_from(&client, addr, secs);
+            let endpoint = IpEndpoint::new(addr, SNTP_PORT);
+            assert_eq!(client.receive(&bytes[..], endpoint, now), Ok(None));
+        }
+        let bytes = reply_from(&client, c, 200);
+        let result = client
+            .receive(&bytes[..], IpEndpoint::new(c, SNTP_PORT), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.offset(), Duration::from_secs(200));
+        assert_eq!(client.collect_until, None);
+
+        // A server didn't reply: the round is closed after the window
+        client.collect_until = Some(now + COLLECT_WINDOW);
+        client.servers.clear_requests();
+        client.servers.get_mut(server()).unwrap().reply = Some(result);
+        client.servers.get_mut(c).unwrap().request_timestamp = Some(Timestamp::default());
+        assert_eq!(client.poll(&mut sockets, now), Ok(None));
+        let result = client.poll(&mut sockets, now + COLLECT_WINDOW).unwrap();
+        assert_eq!(result.unwrap().offset(), Duration::from_secs(200));
+    }
+
+    #[test]
+    fn test_falsetickers() {
+        let (_, mut client) = client();
+        let (b, c) = (IpAddress::v4(192, 168, 1, 2), IpAddress::v4(192, 168, 1, 3));
+        client.add_server(b).unwrap();
+        client.add_server(c).unwrap();
+        client.set_query_mode(QueryMode::Concurrent);
+
+        let now = Instant::from_secs(0);
+        for entry in client.servers.iter_mut() {
+            entry.sent_at = Some(now);
+            entry.request_timestamp = Some(Timestamp::from_unix_micros(0));
+        }
+        for &(addr, secs) in &[(server(), 100), (b, 100), (c, 500)] {
+            let bytes = reply_from(&client, addr, secs);
+            client
+                .receive(&bytes[..], IpEndpoint::new(addr, SNTP_PORT), now)
+                .unwrap();
+        }
+
+        assert_eq!(client.filter.latest().unwrap().offset, 100_000_000);
+        assert!(client.is_falseticker(c));
+        assert!(!client.is_falseticker(b));
+        assert_eq!(client.falsetickers().next(), Some(c));
+
+        // Agreeing again clears the flag
+        for entry in client.servers.iter_mut() {
+            entry.sent_at = Some(now);
+            entry.request_timestamp = Some(Timestamp::from_unix_micros(0));
+        }
+        for &addr in &[server(), b, c] {
+            let bytes = reply_from(&client, addr, 100);
+            client
+                .receive(&bytes[..], IpEndpoint::new(addr, SNTP_PORT), now)
+                .unwrap();
+        }
+        assert!(!client.is_falseticker(c));
+
+        client.servers.get_mut(c).unwrap().falseticker = true;
+        client.clear_falsetickers();
+        assert_eq!(client.falsetickers().next(), None);
+    }
+
+    #[test]
+    fn test_era_rollover() {
+        /// Unix timestamp of the NTP era 1 epoch (Feb 7, 2036).
+        const ERA_1: i64 = (1 << 32) - 2_208_988_800;
+
+        let (_, mut client) = client();
+        let mut bytes = PACKET_BYTES;
+        let mut receive_at = |client: &mut Client, sec: u32| {
+            NetworkEndian::write_u32(&mut bytes[field::TRANSMIT_TIMESTAMP][..4], sec);
+            NetworkEndian::write_u32(&mut bytes[field::REFERENCE_TIMESTAMP][..4], sec - 1);
+            expect_response(client);
+            client
+                .receive(&bytes[..], server_endpoint(), Instant::from_secs(0))
+                .unwrap()
+                .unwrap()
+        };
+
+        // Around Feb 7, 2036
+        let result = receive_at(&mut client, 0xffff_fff0);
+        assert_eq!(result.unix_seconds_u64(), ERA_1 as u64 - 16);
+        let result = receive_at(&mut client, 0x10);
+        assert_eq!(result.unix_seconds_u64(), ERA_1 as u64 + 16);
+        assert_eq!(result.timestamp, ERA_1 as u32 + 16);
+
+        // Around Feb 7, 2106, with an estimate of the current time
+        let wrap = 1u64 << 32;
+        client.seed_offset((wrap as i64 - 100) * 1_000_000, Instant::from_secs(0));
+        let result = receive_at(&mut client, (wrap as i64 - ERA_1) as u32 + 10);
+        assert_eq!(result.unix_seconds_u64(), wrap + 10);
+        assert_eq!(result.timestamp, 10);
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let (_, mut client) = client();
+        let now = Instant::from_secs(0);
+        let recv = |client: &mut Client, endpoint| client.receive(&PACKET_BYTES[..], endpoint, now);
+        expect_response(&mut client);
+        recv(&mut client, server_endpoint()).unwrap().unwrap();
+        let exchange = client.previous_exchange(server()).unwrap();
+        assert_eq!(exchange.client_transmit, Timestamp { sec: 0, frac: 0 });
+        assert_eq!(exchange.client_receive, Instant::from_secs(0));
+
+        // A response to the new request referring to the previous one
+        expect_response(&mut client);
+        client.servers.get_mut(server()).unwrap().request_timestamp =
+            Some(Timestamp { sec: 1, frac: 0 });
+        assert_eq!(recv(&mut client, server_endpoint()), Ok(None));
+        assert_eq!(client.stats().interleaved, 1);
+        assert_eq!(client.stats().wrong_origin, 0);
+    }
 }
@@ -0,0 +1,145 @@
+//! Sources of the local time, and its mapping to the wall-clock time.
+
+use core::convert::TryFrom;
+
+use crate::net::time::Instant;
+use crate::wire::Timestamp;
+
+/// A source of the local monotonic time.
+///
+/// A client holding a clock can be polled without passing the current time
+/// explicitly, see [`Client::poll_now`](struct.Client.html#method.poll_now).
+/// This is mostly useful for tests, which can inject a fake clock.
+///
+/// Any `Fn() -> Instant`, such as a function pointer, implements this trait.
+pub trait Clock {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+impl<F: Fn() -> Instant> Clock for F {
+    fn now(&self) -> Instant {
+        self()
+    }
+}
+
+/// A clock backed by the system time.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A mapping between the local monotonic time and the wall-clock time.
+///
+/// The client works with the local `Instant`, and estimates the wall-clock
+/// time from it: the mapping anchors a local instant to the corresponding Unix
+/// time, and extrapolates it with the rate of the wall clock relative to the
+/// local clock. See [`Client::clock_mapping`].
+///
+/// [`Client::clock_mapping`]: struct.Client.html#method.clock_mapping
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ClockMapping {
+    /// Local instant of the anchor, e.g. when the last sample was received.
+    pub instant: Instant,
+    /// Unix time corresponding to `instant`, in microseconds.
+    pub unix_micros: i64,
+    /// Drift of the local clock, in parts per billion: positive if it runs
+    /// slower than the wall clock.
+    pub drift_ppb: i64,
+}
+
+impl ClockMapping {
+    /// Returns the wall-clock time corresponding to a local instant.
+    pub fn to_wall(&self, instant: Instant) -> Timestamp {
+        let elapsed = (instant.total_millis() - self.instant.total_millis()) * 1_000;
+        let correction = i128::from(self.drift_ppb) * i128::from(elapsed) / 1_000_000_000;
+        Timestamp::from_unix_micros(self.unix_micros + elapsed + correction as i64)
+    }
+
+    /// Returns the local instant corresponding to a wall-clock time, rounded
+    /// to the nearest millisecond.
+    ///
+    /// The NTP era of `wall` is the one closest to the anchor, so this is
+    /// correct for times within 68 years of it.
+    ///
+    /// Returns `None` if `drift_ppb` is -1e9 or less, i.e. if the local clock
+    /// doesn't run forward relative to the wall clock, or if the instant
+    /// doesn't fit an `Instant`.
+    pub fn to_instant(&self, wall: Timestamp) -> Option<Instant> {
+        let rate = 1_000_000_000 + i128::from(self.drift_ppb);
+        if rate <= 0 {
+            return None;
+        }
+        let elapsed = wall.to_unix_micros_near(self.unix_micros) - self.unix_micros;
+        let local = i64::try_from(i128::from(elapsed) * 1_000_000_000 / rate).ok()?;
+        let millis = self
+            .instant
+            .total_millis()
+            .checked_add(local.checked_add(500)?.div_euclid(1_000))?;
+        Some(Instant::from_millis(millis))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_mapping() {
+        let mapping = ClockMapping {
+            instant: Instant::from_secs(100),
+            unix_micros: 1_589_753_489_000_000,
+            drift_ppb: 0,
+        };
+        let wall = mapping.to_wall(Instant::from_secs(100));
+        assert_eq!(wall.to_unix_micros(), 1_589_753_489_000_000);
+        assert_eq!(
+            mapping
+                .to_wall(Instant::from_millis(99_500))
+                .to_unix_micros(),
+            1_589_753_488_500_000
+        );
+        assert_eq!(mapping.to_instant(wall), Some(Instant::from_secs(100)));
+
+        // The local clock runs 100 ppm slow
+        let mapping = ClockMapping {
+            drift_ppb: 100_000,
+            ..mapping
+        };
+        let wall = mapping.to_wall(Instant::from_secs(10_100));
+        assert_eq!(wall.to_unix_micros(), 1_589_763_490_000_000);
+        assert_eq!(mapping.to_instant(wall), Some(Instant::from_secs(10_100)));
+        let wall = mapping.to_wall(Instant::from_secs(-9_900));
+        assert_eq!(wall.to_unix_micros(), 1_589_743_488_000_000);
+        assert_eq!(mapping.to_instant(wall), Some(Instant::from_secs(-9_900)));
+
+        for millis in (0..1_000_000).step_by(999) {
+            let instant = Instant::from_millis(millis);
+            assert_eq!(mapping.to_instant(mapping.to_wall(instant)), Some(instant));
+        }
+
+        // A local clock that doesn't run forward can't be mapped back
+        for &drift_ppb in &[-1_000_000_000, -2_000_000_000, i64::MIN] {
+            let mapping = ClockMapping {
+                drift_ppb,
+                ..mapping
+            };
+            assert_eq!(mapping.to_instant(wall), None);
+        }
+
+        // Nor can instants out of range
+        let mapping = ClockMapping {
+            instant: Instant::from_millis(i64::MAX - 1_000),
+            drift_ppb: -999_999_999,
+            ..mapping
+        };
+        let wall = Timestamp::from_unix_micros(mapping.unix_micros + 1_000_000);
+        assert_eq!(mapping.to_instant(wall), None);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::net;
+use crate::wire::ProtocolMode;
+use core::fmt;
+
+/// The error type for SNTP client operations.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SntpError {
+    /// An error reported by the network stack.
+    Network(net::Error),
+    /// The socket handle does not refer to a UDP socket of the socket set.
+    InvalidSocket,
+    /// The socket is not bound, and automatic binding is disabled.
+    NotBound,
+    /// The socket buffers cannot hold an SNTP packet.
+    /// See [`MIN_BUFFER_LEN`](constant.MIN_BUFFER_LEN.html).
+    BufferTooSmall,
+    /// The socket buffers have no packet metadata slot, and can't hold any packet.
+    NoPacketMetadata,
+    /// The requested poll interval is out of range.
+    InvalidPollInterval,
+    /// The server replied with a kiss-o'-death packet carrying the given code.
+    KissOfDeath([u8; 4]),
+    /// A packet with a mode other than server was received.
+    UnexpectedMode(ProtocolMode),
+    /// A packet with an unsupported protocol version was received.
+    UnsupportedVersion(u8),
+    /// The maximum number of servers has already been configured.
+    /// See [`MAX_SERVERS`](constant.MAX_SERVERS.html).
+    TooManyServers,
+    /// The address does not belong to a configured server.
+    UnknownServer,
+    /// No clock has been set on the client.
+    NoClock,
+    /// A configuration value is out of the range allowed by the protocol.
+    OutOfRange,
+    /// All the configured servers are blocked, and no request can be sent.
+    ///
+    /// The client can't recover on its own: servers must be unblocked with
+    /// [`Client::unblock_server`] or [`Client::reset`], or new ones added.
+    ///
+    /// [`Client::unblock_server`]: struct.Client.html#method.unblock_server
+    /// [`Client::reset`]: struct.Client.html#method.reset
+    NoServersAvailable,
+    /// No valid response was received before the deadline.
+    TimedOut,
+    /// The address can't be the one of a unicast server, e.g. it is a
+    /// broadcast or multicast address.
+    InvalidAddress,
+    /// The client has not been synchronized yet.
+    NotSynchronized,
+    /// The packet is larger than the configured maximum.
+    /// See [`MAX_PACKET_LEN`](constant.MAX_PACKET_LEN.html).
+    PacketTooLarge,
+}
+
+/// The result type for SNTP client operations.
+pub type Result<T> = core::result::Result<T, SntpError>;
+
+impl From<net::Error> for SntpError {
+    fn from(err: net::Error) -> Self {
+        SntpError::Network(err)
+    }
+}
+
+impl fmt::Display for SntpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SntpError::Network(err) => write!(f, "network error: {}", err),
+            SntpError::InvalidSocket => write!(f, "handle does not refer to a UDP socket"),
+            SntpError::NotBound => write!(f, "socket not bound"),
+            SntpError::BufferTooSmall => write!(f, "socket buffer too small"),
+            SntpError::NoPacketMetadata => write!(f, "socket buffer without packet metadata"),
+            SntpError::InvalidPollInterval => write!(f, "invalid poll interval"),
+            SntpError::KissOfDeath(code) => {
+                write!(f, "kiss o' death received: ")?;
+                code.iter().try_for_each(|&c| write!(f, "{}", c as char))
+            }
+            SntpError::UnexpectedMode(mode) => write!(f, "unexpected mode: {:?}", mode),
+            SntpError::UnsupportedVersion(vn) => write!(f, "unsupported version: {}", vn),
+            SntpError::TooManyServers => write!(f, "too many servers"),
+            SntpError::UnknownServer => write!(f, "unknown server"),
+            SntpError::NoClock => write!(f, "no clock set"),
+            SntpError::OutOfRange => write!(f, "value out of range"),
+            SntpError::NoServersAvailable => write!(f, "no server available"),
+            SntpError::TimedOut => write!(f, "timed out"),
+            SntpError::PacketTooLarge => write!(f, "packet too large"),
+            SntpError::InvalidAddress => write!(f, "invalid server address"),
+            SntpError::NotSynchronized => write!(f, "not synchronized"),
+        }
+    }
+}
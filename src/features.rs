@@ -0,0 +1,25 @@
+//! Optional features the crate was compiled with.
+
+/// The optional features the crate was compiled with.
+///
+/// See the crate documentation for the description of each feature.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FeatureFlags {
+    /// Logging of network activity (`log` feature).
+    pub log: bool,
+    /// `std` support (`std` feature), e.g. [`SystemClock`].
+    ///
+    /// [`SystemClock`]: struct.SystemClock.html
+    pub std: bool,
+}
+
+/// Returns the optional features the crate was compiled with.
+///
+/// This is resolved at compile time, and is meant for diagnostics, e.g. to
+/// report the capabilities of a deployed firmware.
+pub const fn features() -> FeatureFlags {
+    FeatureFlags {
+        log: cfg!(feature = "log"),
+        std: cfg!(feature = "std"),
+    }
+}
@@ -0,0 +1,254 @@
+//! Storage for the most recent synchronization samples.
+
+use crate::net::time::Instant;
+
+/// Default number of samples retained by the client.
+pub const DEFAULT_SAMPLE_COUNT: usize = 8;
+
+/// Which offset the client reports for each accepted response.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SampleSelection {
+    /// Report the offset and delay measured with the response itself.
+    ///
+    /// The first valid response is reported as is, which is best for a fast
+    /// cold start, but each result carries the noise of a single measurement.
+    /// This is the default.
+    Latest,
+    /// Report the offset and delay of the retained sample with the shortest
+    /// round-trip delay, which is the least affected by network jitter.
+    ///
+    /// This is more stable, but a good sample can be reported for a while
+    /// after it was measured, and the first results are no better than with
+    /// [`SampleSelection::Latest`].
+    Filtered,
+}
+
+impl Default for SampleSelection {
+    fn default() -> Self {
+        SampleSelection::Latest
+    }
+}
+
+/// A single measurement of the server clock.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Sample {
+    /// Offset of the server clock relative to the local clock, in microseconds.
+    ///
+    /// Adding this value to a local `Instant` yields the corresponding Unix time.
+    pub offset: i64,
+    /// Round-trip delay of the exchange, in microseconds.
+    pub delay: i64,
+    /// Local instant at which the response was received.
+    pub received_at: Instant,
+}
+
+/// A fixed-size ring of the `N` most recent samples, oldest first.
+#[derive(Debug)]
+pub(crate) struct ClockFilter<const N: usize> {
+    samples: [Option<Sample>; N],
+    /// Index of the slot that will hold the next sample.
+    next: usize,
+}
+
+impl<const N: usize> ClockFilter<N> {
+    /// Creates an empty filter.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `N` is zero.
+    pub(crate) fn new() -> ClockFilter<N> {
+        assert!(N > 0, "the clock filter must hold at least one sample");
+        ClockFilter {
+            samples: [None; N],
+            next: 0,
+        }
+    }
+
+    /// Stores a new sample, evicting the oldest one if the filter is full.
+    pub(crate) fn push(&mut self, sample: Sample) {
+        self.samples[self.next] = Some(sample);
+        self.next = (self.next + 1) % N;
+    }
+
+    /// Removes all samples.
+    pub(crate) fn clear(&mut self) {
+        *self = ClockFilter::new();
+    }
+
+    /// Returns the most recent sample, if any.
+    pub(crate) fn latest(&self) -> Option<&Sample> {
+        let idx = (self.next + N - 1) % N;
+        self.samples[idx].as_ref()
+    }
+
+    /// Returns the sample with the shortest round-trip delay, the most recent
+    /// one in case of a tie, if any.
+    pub(crate) fn best(&self) -> Option<&Sample> {
+        self.iter().fold(None, |best, sample| match best {
+            Some(best) if best.delay < sample.delay => Some(best),
+            _ => Some(sample),
+        })
+    }
+
+    /// Returns the oldest stored sample, if any.
+    pub(crate) fn oldest(&self) -> Option<&Sample> {
+        self.iter().next()
+    }
+
+    /// Estimates the drift of the local clock relative to the server clock,
+    /// in parts per billion, from the oldest and the most recent samples.
+    ///
+    /// A positive value means that the offset grows over time, i.e. the local
+    /// clock runs slower than the server clock.
+    pub(crate) fn drift_ppb(&self) -> Option<i64> {
+        let (oldest, latest) = (self.oldest()?, self.latest()?);
+        let elapsed =
+            (latest.received_at.total_millis() - oldest.received_at.total_millis()) * 1_000;
+        if elapsed <= 0 {
+            return None;
+        }
+        let drift = i128::from(latest.offset - oldest.offset) * 1_000_000_000 / i128::from(elapsed);
+        Some(drift as i64)
+    }
+
+    /// Estimates the offset at the given instant, extrapolating from the most
+    /// recent sample with the estimated drift.
+    pub(crate) fn offset_at(&self, now: Instant) -> Option<i64> {
+        let latest = self.latest()?;
+        let elapsed = (now.total_millis() - latest.received_at.total_millis()) * 1_000;
+        let drift = self.drift_ppb().unwrap_or(0);
+        let correction = i128::from(drift) * i128::from(elapsed) / 1_000_000_000;
+        Some(latest.offset + correction as i64)
+    }
+
+    /// Returns the jitter of the offsets, in microseconds.
+    ///
+    /// As in RFC 5905, this is the root mean square of the differences between
+    /// the offsets of the other samples and the one of the best sample (see
+    /// [`best`](#method.best)), and zero for less than two samples.
+    pub(crate) fn jitter(&self) -> i64 {
+        let best = match self.best() {
+            Some(best) => best.offset,
+            None => return 0,
+        };
+        let (count, sum) = self.iter().fold((0u32, 0u128), |(count, sum), sample| {
+            let diff = u128::from((sample.offset - best).unsigned_abs());
+            (count + 1, sum + diff * diff)
+        });
+        if count < 2 {
+            return 0;
+        }
+        isqrt(sum / u128::from(count - 1)) as i64
+    }
+
+    /// Returns an iterator over the stored samples, from the oldest to the newest.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Sample> {
+        let (newer, older) = self.samples.split_at(self.next);
+        older.iter().chain(newer.iter()).filter_map(Option::as_ref)
+    }
+}
+
+/// Returns the square root of `value`, rounded down.
+fn isqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+    // Newton's method, starting above the root
+    let mut root = value;
+    let mut next = (root + 1) / 2;
+    while next < root {
+        root = next;
+        next = (root + value / root) / 2;
+    }
+    root
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(offset: i64) -> Sample {
+        Sample {
+            offset,
+            delay: 0,
+            received_at: Instant::from_millis(offset),
+        }
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut filter = ClockFilter::<4>::new();
+        let at = |secs, offset| Sample {
+            offset,
+            delay: 0,
+            received_at: Instant::from_secs(secs),
+        };
+
+        filter.push(at(0, 1_000_000));
+        assert_eq!(filter.drift_ppb(), None);
+        assert_eq!(filter.offset_at(Instant::from_secs(100)), Some(1_000_000));
+
+        // 50 us gained over 1000 s, i.e. 50 ppb
+        filter.push(at(1_000, 1_000_050));
+        assert_eq!(filter.drift_ppb(), Some(50));
+        assert_eq!(filter.offset_at(Instant::from_secs(3_000)), Some(1_000_150));
+    }
+
+    #[test]
+    fn test_best() {
+        let mut filter = ClockFilter::<4>::new();
+        assert_eq!(filter.best(), None);
+
+        let with_delay = |offset, delay| Sample {
+            delay,
+            ..sample(offset)
+        };
+        filter.push(with_delay(0, 30));
+        filter.push(with_delay(1, 10));
+        filter.push(with_delay(2, 20));
+        assert_eq!(filter.best(), Some(&with_delay(1, 10)));
+        filter.push(with_delay(3, 10));
+        assert_eq!(filter.best(), Some(&with_delay(3, 10)));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut filter = ClockFilter::<4>::new();
+        assert_eq!(filter.jitter(), 0);
+        filter.push(sample(100));
+        assert_eq!(filter.jitter(), 0);
+
+        // All samples have the same delay, so the newest one is the best
+        filter.push(sample(97));
+        assert_eq!(filter.jitter(), 3);
+        filter.push(sample(100));
+        filter.push(sample(104));
+        // sqrt((16 + 49 + 16) / 3)
+        assert_eq!(filter.jitter(), 5);
+
+        for value in [0, 1, 2, 3, 4, 15, 16, 17, 1 << 40, u128::from(u64::MAX)] {
+            let root = isqrt(value);
+            assert!(root * root <= value && (root + 1) * (root + 1) > value);
+        }
+    }
+
+    #[test]
+    fn test_ring_order() {
+        let mut filter = ClockFilter::<4>::new();
+        assert_eq!(filter.iter().count(), 0);
+        assert_eq!(filter.latest(), None);
+
+        for i in 0..3 {
+            filter.push(sample(i));
+        }
+        let offsets: std::vec::Vec<_> = filter.iter().map(|s| s.offset).collect();
+        assert_eq!(offsets, [0, 1, 2]);
+
+        for i in 3..(4 + 2) {
+            filter.push(sample(i));
+        }
+        let offsets: std::vec::Vec<_> = filter.iter().map(|s| s.offset).collect();
+        assert_eq!(offsets, (2..(4 + 2)).collect::<std::vec::Vec<_>>());
+        assert_eq!(filter.latest(), Some(&sample(4 + 1)));
+    }
+}
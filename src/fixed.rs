@@ -0,0 +1,292 @@
+//! Conversions between the fixed-point formats of the SNTP header and durations.
+//!
+//! The root delay and root dispersion are sent in the NTP short format, i.e.
+//! 16.16 fixed-point seconds, while the poll interval and the precision are
+//! signed base-2 logarithms of seconds. Timestamps carry the fraction of
+//! second in units of 2^-32 s.
+//!
+//! Since `Duration` has a millisecond resolution, conversions to it are
+//! lossy: the rounding direction is documented on each function.
+
+use core::convert::TryFrom;
+
+use crate::net::time::Duration;
+
+/// Largest log2 seconds value whose duration fits in a `Duration`.
+const MAX_LOG2_SECS: i8 = 53;
+
+/// Converts a value in NTP short format to a `Duration`, rounded down to the millisecond.
+pub fn short_to_duration(short: u32) -> Duration {
+    Duration::from_millis((u64::from(short) * 1_000) >> 16)
+}
+
+/// Converts a `Duration` to NTP short format, rounded down.
+///
+/// Returns `None` if the duration is too long to be represented, i.e. 65536 s or more.
+pub fn duration_to_short(duration: Duration) -> Option<u32> {
+    let short = (u128::from(duration.total_millis()) << 16) / 1_000;
+    u32::try_from(short).ok()
+}
+
+/// Converts a log2 seconds value to a `Duration`.
+///
+/// Intervals shorter than a millisecond are rounded up, so that e.g. a clock
+/// precision is never reported as zero. Values too large for a `Duration`
+/// saturate.
+pub fn log2_secs_to_duration(log2_secs: i8) -> Duration {
+    if log2_secs > MAX_LOG2_SECS {
+        Duration::from_millis(u64::MAX)
+    } else if log2_secs >= 0 {
+        Duration::from_secs(1 << log2_secs)
+    } else {
+        Duration::from_millis(ceil_shr(1_000, -i32::from(log2_secs)))
+    }
+}
+
+/// Converts a `Duration` to log2 seconds, rounded up.
+///
+/// Returns the smallest exponent whose interval is at least `duration`, and
+/// `i8::MIN` for a zero duration.
+pub fn duration_to_log2_secs(duration: Duration) -> i8 {
+    let millis = u128::from(duration.total_millis());
+    if millis == 0 {
+        return i8::MIN;
+    }
+
+    let mut log2_secs = 0;
+    if millis > 1_000 {
+        while 1_000 << log2_secs < millis {
+            log2_secs += 1;
+        }
+    } else {
+        while millis << (1 - log2_secs) <= 1_000 {
+            log2_secs -= 1;
+        }
+    }
+    log2_secs
+}
+
+/// Converts the sub-second part of a `Duration` to an NTP timestamp fraction,
+/// in units of 2^-32 s, rounded to the nearest unit.
+///
+/// Whole seconds are discarded: they belong in the seconds field of the
+/// timestamp. Converting the result back with [`duration_from_ntp_frac`]
+/// yields the sub-second part of `duration` again.
+///
+/// [`duration_from_ntp_frac`]: fn.duration_from_ntp_frac.html
+pub fn ntp_frac_from_duration(duration: Duration) -> u32 {
+    let millis = duration.millis();
+    (((millis << 32) + 500) / 1_000) as u32
+}
+
+/// Converts an NTP timestamp fraction, in units of 2^-32 s, to a `Duration`,
+/// rounded to the nearest millisecond.
+///
+/// Fractions within half a millisecond of the next second round up to 1 s.
+pub fn duration_from_ntp_frac(frac: u32) -> Duration {
+    Duration::from_millis((u64::from(frac) * 1_000 + (1 << 31)) >> 32)
+}
+
+/// Converts the sub-second part of a number of microseconds to an NTP
+/// timestamp fraction, in units of 2^-32 s, rounded to the nearest unit.
+///
+/// Whole seconds are discarded: negative values count back from the next
+/// whole second, e.g. -1 µs is converted like 999 999 µs. Converting the
+/// result back with [`micros_from_ntp_frac`] yields the sub-second part again.
+///
+/// [`micros_from_ntp_frac`]: fn.micros_from_ntp_frac.html
+pub fn ntp_frac_from_micros(micros: i64) -> u32 {
+    let micros = micros.rem_euclid(1_000_000) as u64;
+    (((micros << 32) + 500_000) / 1_000_000) as u32
+}
+
+/// Converts an NTP timestamp fraction, in units of 2^-32 s, to microseconds,
+/// rounded to the nearest microsecond.
+///
+/// Fractions within half a microsecond of the next second round up to 1 s.
+pub fn micros_from_ntp_frac(frac: u32) -> i64 {
+    ((u64::from(frac) * 1_000_000 + (1 << 31)) >> 32) as i64
+}
+
+/// Converts a signed 32.32 fixed-point number of seconds, e.g. the difference
+/// of two timestamps, to microseconds, rounded to the nearest microsecond.
+pub(crate) fn ntp_fixed_to_micros(fixed: i64) -> i64 {
+    (fixed >> 32) * 1_000_000 + micros_from_ntp_frac(fixed as u32)
+}
+
+/// Converts a value in NTP short format to microseconds, rounded down.
+pub(crate) fn short_to_micros(short: i64) -> i64 {
+    (short * 1_000_000) >> 16
+}
+
+/// Converts a log2 seconds value to microseconds, rounded up.
+pub(crate) fn log2_secs_to_micros(log2_secs: i8) -> i64 {
+    if log2_secs >= 0 {
+        1_000_000 << log2_secs.min(32)
+    } else {
+        ceil_shr(1_000_000, (-i32::from(log2_secs)).min(62)) as i64
+    }
+}
+
+/// Divides `value` by `2^shift`, rounded up.
+fn ceil_shr(value: u64, shift: i32) -> u64 {
+    let shift = shift.min(63);
+    (value + (1 << shift) - 1) >> shift
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_short() {
+        assert_eq!(short_to_duration(0), Duration::from_millis(0));
+        assert_eq!(short_to_duration(0x0001_0000), Duration::from_secs(1));
+        assert_eq!(short_to_duration(0x0000_8000), Duration::from_millis(500));
+        // 1/65536 s is about 15 us
+        assert_eq!(short_to_duration(1), Duration::from_millis(0));
+        assert_eq!(short_to_duration(66), Duration::from_millis(1));
+        assert_eq!(
+            short_to_duration(u32::MAX),
+            Duration::from_millis(65_535_999)
+        );
+
+        assert_eq!(duration_to_short(Duration::from_millis(0)), Some(0));
+        assert_eq!(duration_to_short(Duration::from_secs(1)), Some(0x0001_0000));
+        assert_eq!(
+            duration_to_short(Duration::from_millis(500)),
+            Some(0x0000_8000)
+        );
+        assert_eq!(duration_to_short(Duration::from_millis(1)), Some(65));
+        assert_eq!(
+            duration_to_short(Duration::from_millis(65_535_999)),
+            Some(0xffff_ffbe)
+        );
+        assert_eq!(duration_to_short(Duration::from_secs(65_536)), None);
+
+        for millis in (0..65_536_000).step_by(997) {
+            let short = duration_to_short(Duration::from_millis(millis)).unwrap();
+            let back = short_to_duration(short).total_millis();
+            assert!(back == millis || back + 1 == millis);
+        }
+
+        assert_eq!(short_to_micros(0x0001_0000), 1_000_000);
+        assert_eq!(short_to_micros(1), 15);
+        assert_eq!(short_to_micros(-0x0000_8000), -500_000);
+    }
+
+    #[test]
+    fn test_ntp_frac() {
+        assert_eq!(ntp_frac_from_duration(Duration::from_millis(0)), 0);
+        assert_eq!(
+            ntp_frac_from_duration(Duration::from_millis(500)),
+            0x8000_0000
+        );
+        // 1 ms is 4294967.296 units, 2 ms is 8589934.592 units
+        assert_eq!(ntp_frac_from_duration(Duration::from_millis(1)), 4_294_967);
+        assert_eq!(ntp_frac_from_duration(Duration::from_millis(2)), 8_589_935);
+        assert_eq!(
+            ntp_frac_from_duration(Duration::from_millis(999)),
+            4_290_672_329
+        );
+        // Whole seconds are discarded
+        assert_eq!(
+            ntp_frac_from_duration(Duration::from_millis(3_500)),
+            0x8000_0000
+        );
+
+        assert_eq!(duration_from_ntp_frac(0), Duration::from_millis(0));
+        assert_eq!(
+            duration_from_ntp_frac(0x8000_0000),
+            Duration::from_millis(500)
+        );
+        assert_eq!(duration_from_ntp_frac(2_147_483), Duration::from_millis(0));
+        assert_eq!(duration_from_ntp_frac(2_147_484), Duration::from_millis(1));
+        assert_eq!(duration_from_ntp_frac(u32::MAX), Duration::from_secs(1));
+
+        for millis in 0..1_000 {
+            let duration = Duration::from_millis(millis);
+            let frac = ntp_frac_from_duration(duration);
+            assert_eq!(duration_from_ntp_frac(frac), duration);
+            assert_eq!(ntp_frac_from_duration(duration_from_ntp_frac(frac)), frac);
+        }
+        for frac in (0..=u32::MAX).step_by(65_537) {
+            let back = ntp_frac_from_duration(duration_from_ntp_frac(frac));
+            // Within half a millisecond, modulo one second
+            let error = back.wrapping_sub(frac) as i32;
+            assert!(error.unsigned_abs() <= 2_147_484);
+        }
+    }
+
+    #[test]
+    fn test_ntp_frac_micros() {
+        assert_eq!(ntp_frac_from_micros(0), 0);
+        assert_eq!(ntp_frac_from_micros(500_000), 0x8000_0000);
+        // 1 us is 4294.967296 units
+        assert_eq!(ntp_frac_from_micros(1), 4_295);
+        assert_eq!(ntp_frac_from_micros(999_999), 4_294_963_001);
+        // Whole seconds are discarded
+        assert_eq!(ntp_frac_from_micros(3_500_000), 0x8000_0000);
+        assert_eq!(ntp_frac_from_micros(-500_000), 0x8000_0000);
+        assert_eq!(ntp_frac_from_micros(-1), ntp_frac_from_micros(999_999));
+
+        assert_eq!(micros_from_ntp_frac(0), 0);
+        assert_eq!(micros_from_ntp_frac(0x8000_0000), 500_000);
+        assert_eq!(micros_from_ntp_frac(2_147), 0);
+        assert_eq!(micros_from_ntp_frac(2_148), 1);
+        assert_eq!(micros_from_ntp_frac(u32::MAX), 1_000_000);
+
+        for micros in (0..1_000_000).step_by(7) {
+            assert_eq!(micros_from_ntp_frac(ntp_frac_from_micros(micros)), micros);
+        }
+
+        assert_eq!(ntp_fixed_to_micros(0x0001_8000_0000), 1_500_000);
+        assert_eq!(ntp_fixed_to_micros(-0x0000_8000_0000), -500_000);
+        assert_eq!(ntp_fixed_to_micros(-0x0001_8000_0000), -1_500_000);
+        assert_eq!(ntp_fixed_to_micros(-1), 0);
+    }
+
+    #[test]
+    fn test_log2_secs() {
+        assert_eq!(log2_secs_to_duration(0), Duration::from_secs(1));
+        assert_eq!(log2_secs_to_duration(6), Duration::from_secs(64));
+        assert_eq!(log2_secs_to_duration(17), Duration::from_secs(131_072));
+        assert_eq!(log2_secs_to_duration(-1), Duration::from_millis(500));
+        assert_eq!(log2_secs_to_duration(-3), Duration::from_millis(125));
+        assert_eq!(log2_secs_to_duration(-4), Duration::from_millis(63));
+        assert_eq!(log2_secs_to_duration(-10), Duration::from_millis(1));
+        assert_eq!(log2_secs_to_duration(-20), Duration::from_millis(1));
+        assert_eq!(log2_secs_to_duration(i8::MIN), Duration::from_millis(1));
+        assert_eq!(
+            log2_secs_to_duration(MAX_LOG2_SECS),
+            Duration::from_secs(1 << MAX_LOG2_SECS)
+        );
+        assert_eq!(
+            log2_secs_to_duration(i8::MAX),
+            Duration::from_millis(u64::MAX)
+        );
+
+        assert_eq!(duration_to_log2_secs(Duration::from_millis(0)), i8::MIN);
+        assert_eq!(duration_to_log2_secs(Duration::from_millis(1)), -9);
+        assert_eq!(duration_to_log2_secs(Duration::from_millis(2)), -8);
+        assert_eq!(duration_to_log2_secs(Duration::from_millis(500)), -1);
+        assert_eq!(duration_to_log2_secs(Duration::from_millis(501)), 0);
+        assert_eq!(duration_to_log2_secs(Duration::from_secs(1)), 0);
+        assert_eq!(duration_to_log2_secs(Duration::from_millis(1_001)), 1);
+        assert_eq!(duration_to_log2_secs(Duration::from_secs(64)), 6);
+        assert_eq!(duration_to_log2_secs(Duration::from_secs(65)), 7);
+        assert_eq!(duration_to_log2_secs(Duration::from_millis(u64::MAX)), 55);
+
+        // Shorter intervals aren't a whole number of milliseconds
+        for log2_secs in -3..=MAX_LOG2_SECS {
+            let duration = log2_secs_to_duration(log2_secs);
+            assert_eq!(duration_to_log2_secs(duration), log2_secs);
+        }
+
+        assert_eq!(log2_secs_to_micros(0), 1_000_000);
+        assert_eq!(log2_secs_to_micros(2), 4_000_000);
+        assert_eq!(log2_secs_to_micros(-10), 977);
+        assert_eq!(log2_secs_to_micros(-20), 1);
+        assert_eq!(log2_secs_to_micros(i8::MIN), 1);
+    }
+}
@@ -5,6 +5,13 @@ heap allocations, and does not rely on the `alloc` feature of `smoltcp`.
 
 For convenience, this crate re-exports `smoltcp` under the `net` name.
 
+A minimal [`Server`] is also provided, to serve the time of a local reference
+clock, e.g. on isolated networks.
+
+The [`wire`] module gives access to the SNTP packet format, and can be used
+independently of the client, e.g. with a different transport. The [`fixed`]
+module converts the fixed-point header fields to and from durations.
+
 # Examples
 
 An example on how to use this crate can be found in the source repository.
@@ -19,6 +26,15 @@ Enable logging for network activity. Useful to debug the client operation.
 
 Disabled by default
 
+## `std`
+
+Enable `std` support in `smoltcp`, and the blocking `std_util` helpers to
+query a server over a `std::net::UdpSocket` or a `smoltcp` interface.
+
+Disabled by default
+
+The features enabled in a build can be checked at runtime with [`features`].
+
 [`smoltcp`]: https://github.com/smoltcp-rs/smoltcp
 */
 
@@ -28,7 +44,7 @@ Disabled by default
 #![no_std]
 
 #[cfg(any(test, feature = "std"))]
-#[macro_use]
+#[cfg_attr(test, macro_use)]
 extern crate std;
 
 #[cfg(feature = "log")]
@@ -41,7 +57,31 @@ pub use smoltcp as net;
 #[macro_use]
 mod macros;
 mod client;
-mod wire;
+mod clock;
+mod error;
+mod features;
+mod filter;
+pub mod fixed;
+mod server;
+mod servers;
+mod socket;
+mod stats;
+#[cfg(feature = "std")]
+pub mod std_util;
+pub mod wire;
 
 // Export public types
-pub use client::Client;
+pub use client::{
+    Client, DefaultClient, PollSchedule, SyncResult, SyncState, MAX_PACKET_LEN, MIN_BUFFER_LEN,
+    SYNC_RESULT_RECORD_LEN,
+};
+#[cfg(feature = "std")]
+pub use clock::SystemClock;
+pub use clock::{Clock, ClockMapping};
+pub use error::{Result, SntpError};
+pub use features::{features, FeatureFlags};
+pub use filter::{Sample, SampleSelection, DEFAULT_SAMPLE_COUNT};
+pub use server::Server;
+pub use servers::{BlockReason, Exchange, QueryMode, RetryPolicy, SwitchReason, MAX_SERVERS};
+pub use stats::Stats;
+pub use wire::ProtocolMode;
@@ -0,0 +1,481 @@
+use core::convert::TryFrom;
+
+use crate::client::{Client, DEFAULT_PRECISION, SNTP_PORT};
+use crate::error::{Result, SntpError};
+use crate::fixed::duration_to_short;
+use crate::net::{
+    socket::{SocketHandle, SocketSet, UdpSocket, UdpSocketBuffer},
+    time::{Duration, Instant},
+    wire::{IpAddress, IpEndpoint},
+    Error,
+};
+use crate::socket::{check_buffers, check_socket};
+use crate::wire::{
+    LeapIndicator, Packet, ProtocolMode, Repr, Stratum, Timestamp, MIN_EMIT_VERSION, NTP_VERSION_4,
+};
+
+/// Minimal SNTPv4 server.
+///
+/// The server answers the requests of SNTP clients with the time of a local
+/// reference clock, e.g. on an isolated network without access to public
+/// servers. You must call `Server::poll()` after `Interface::poll()` to
+/// receive requests and send replies.
+///
+/// By default, the server advertises itself as a primary server (stratum 1)
+/// synchronized to an uncalibrated local clock (`LOCL`), with a zero root
+/// delay and root dispersion. A device that obtains its time from upstream
+/// servers with a [`Client`] is a secondary server instead: see [`relay`].
+///
+/// [`Client`]: struct.Client.html
+/// [`relay`]: #method.relay
+pub struct Server {
+    udp_handle: SocketHandle,
+    /// Stratum advertised in replies.
+    stratum: Stratum,
+    /// Reference identifier advertised in replies.
+    ref_identifier: [u8; 4],
+    /// Time the local clock was last set, or `None` to advertise the current time.
+    ref_timestamp: Option<Timestamp>,
+    /// Root delay advertised in replies, as 16.16 fixed-point seconds.
+    root_delay: i32,
+    /// Root dispersion advertised in replies, as 16.16 fixed-point seconds.
+    root_dispersion: u32,
+    /// Precision of the local clock, as log2 seconds.
+    precision: i8,
+}
+
+impl Server {
+    /// Create a new SNTPv4 server.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `sockets` uses fixed-size storage and has no free slot,
+    /// or if the buffers are unusable.
+    /// See [`Client::new`](struct.Client.html#method.new).
+    pub fn new<'a, 'b, 'c>(
+        sockets: &mut SocketSet<'a, 'b, 'c>,
+        rx_buffer: UdpSocketBuffer<'b, 'c>,
+        tx_buffer: UdpSocketBuffer<'b, 'c>,
+    ) -> Self
+    where
+        'b: 'c,
+    {
+        let socket = UdpSocket::new(rx_buffer, tx_buffer);
+        if let Err(err) = check_buffers(&socket) {
+            panic!("invalid SNTP socket buffers: {}", err);
+        }
+        let udp_handle = sockets.add(socket);
+
+        Self::from_handle(udp_handle)
+    }
+
+    /// Create a new SNTPv4 server using a UDP socket already added to `sockets`.
+    ///
+    /// Returns `Err(SntpError::InvalidSocket)` if `udp_handle` does not refer
+    /// to a UDP socket of `sockets`, and `Err(SntpError::BufferTooSmall)` if its
+    /// buffers are smaller than [`MIN_BUFFER_LEN`] or
+    /// `Err(SntpError::NoPacketMetadata)` if they have no packet metadata slot.
+    ///
+    /// [`MIN_BUFFER_LEN`]: constant.MIN_BUFFER_LEN.html
+    pub fn with_socket(sockets: &SocketSet, udp_handle: SocketHandle) -> Result<Self> {
+        check_socket(sockets, udp_handle)?;
+
+        Ok(Self::from_handle(udp_handle))
+    }
+
+    fn from_handle(udp_handle: SocketHandle) -> Self {
+        net_trace!("SNTP server initialised");
+
+        Server {
+            udp_handle,
+            stratum: Stratum::Primary,
+            ref_identifier: *b"LOCL",
+            ref_timestamp: None,
+            root_delay: 0,
+            root_dispersion: 0,
+            precision: DEFAULT_PRECISION,
+        }
+    }
+
+    /// Sets the stratum advertised in replies.
+    ///
+    /// A server synchronized to a reference clock (e.g. GPS) is a primary
+    /// server; one synchronized to another server has a stratum one higher
+    /// than its upstream server. Returns `Err(SntpError::OutOfRange)` if the
+    /// stratum is neither primary nor secondary.
+    pub fn set_stratum(&mut self, stratum: Stratum) -> Result<()> {
+        match stratum {
+            Stratum::Primary | Stratum::Secondary(_) => {
+                self.stratum = stratum;
+                Ok(())
+            }
+            Stratum::KissOfDeath | Stratum::Reserved(_) => Err(SntpError::OutOfRange),
+        }
+    }
+
+    /// Sets the reference identifier advertised in replies.
+    ///
+    /// For a primary server, this is a four-character code identifying the
+    /// reference clock, e.g. `*b"GPS "`. For a secondary server, this is the
+    /// IPv4 address of the upstream server. Defaults to `LOCL`.
+    /// See also [`Repr::set_reference_code`] and [`Repr::set_reference_ip`].
+    ///
+    /// [`Repr::set_reference_code`]: wire/struct.Repr.html#method.set_reference_code
+    /// [`Repr::set_reference_ip`]: wire/struct.Repr.html#method.set_reference_ip
+    pub fn set_ref_identifier(&mut self, id: [u8; 4]) {
+        self.ref_identifier = id;
+    }
+
+    /// Sets the time the local clock was last set or corrected.
+    ///
+    /// Pass `None` to advertise the current time, which is the default.
+    pub fn set_ref_timestamp(&mut self, timestamp: Option<Timestamp>) {
+        self.ref_timestamp = timestamp;
+    }
+
+    /// Sets the root delay advertised in replies, i.e. the round-trip delay
+    /// to the primary reference. This is zero for primary servers.
+    ///
+    /// Returns `Err(SntpError::OutOfRange)` if `delay` doesn't fit the
+    /// 16.16 fixed-point format of the field (about 32768 s).
+    pub fn set_root_delay(&mut self, delay: Duration) -> Result<()> {
+        let delay = duration_to_short(delay).ok_or(SntpError::OutOfRange)?;
+        self.root_delay = i32::try_from(delay).map_err(|_| SntpError::OutOfRange)?;
+        Ok(())
+    }
+
+    /// Sets the root dispersion advertised in replies, i.e. the maximum error
+    /// relative to the primary reference.
+    ///
+    /// Returns `Err(SntpError::OutOfRange)` if `dispersion` doesn't fit the
+    /// 16.16 fixed-point format of the field (about 65536 s).
+    pub fn set_root_dispersion(&mut self, dispersion: Duration) -> Result<()> {
+        self.root_dispersion = duration_to_short(dispersion).ok_or(SntpError::OutOfRange)?;
+        Ok(())
+    }
+
+    /// Advertises the time obtained by `client`, as a secondary server.
+    ///
+    /// The stratum is the one of the upstream server plus one, the reference
+    /// identifier the IPv4 address of the server whose sample was last
+    /// accepted, e.g. the selected reply in concurrent mode (zero if unknown
+    /// or IPv6), and the root delay and root dispersion those computed by the
+    /// client at `now` (see [`Client::root_delay`] and
+    /// [`Client::root_dispersion`]). Since the root dispersion grows with the
+    /// time elapsed since the last sample, this should be called again before
+    /// each [`poll`], or at least after each synchronization.
+    ///
+    /// Returns `Err(SntpError::NotSynchronized)` if the client has never been
+    /// synchronized, and `Err(SntpError::OutOfRange)` if the upstream server
+    /// has the maximum stratum (15) or the values don't fit the fields. The
+    /// advertised values are left unchanged in these cases.
+    ///
+    /// [`Client::root_delay`]: struct.Client.html#method.root_delay
+    /// [`Client::root_dispersion`]: struct.Client.html#method.root_dispersion
+    /// [`poll`]: #method.poll
+    pub fn relay<const N: usize, C>(&mut self, client: &Client<N, C>, now: Instant) -> Result<()> {
+        let upstream = client.last_stratum().ok_or(SntpError::NotSynchronized)?;
+        let root_delay = client.root_delay().ok_or(SntpError::NotSynchronized)?;
+        let root_dispersion = client
+            .root_dispersion(now)
+            .ok_or(SntpError::NotSynchronized)?;
+
+        let stratum = Stratum::from(upstream.max(1) + 1);
+        let ref_identifier = match client.last_server() {
+            Some(IpAddress::Ipv4(addr)) => addr.0,
+            _ => [0; 4],
+        };
+        let root_delay = duration_to_short(root_delay)
+            .and_then(|delay| i32::try_from(delay).ok())
+            .ok_or(SntpError::OutOfRange)?;
+        let root_dispersion = duration_to_short(root_dispersion).ok_or(SntpError::OutOfRange)?;
+
+        self.set_stratum(stratum)?;
+        self.ref_identifier = ref_identifier;
+        self.root_delay = root_delay;
+        self.root_dispersion = root_dispersion;
+        Ok(())
+    }
+
+    /// Answers the pending requests with the given time of the local clock.
+    ///
+    /// `now` is used both as the receive and the transmit timestamp of the
+    /// replies, so this should be called as soon as possible after
+    /// `Interface::poll()`. Packets other than client requests are discarded.
+    ///
+    /// Returns the number of replies sent. Requests that can't be answered
+    /// because the transmit buffer is full are left for the next poll.
+    pub fn poll(&mut self, sockets: &mut SocketSet, now: Timestamp) -> Result<usize> {
+        let mut socket = sockets.get::<UdpSocket>(self.udp_handle);
+
+        check_buffers(&socket)?;
+
+        // Bind the socket if necessary
+        if !socket.is_open() {
+            socket.bind(IpEndpoint {
+                addr: IpAddress::Unspecified,
+                port: SNTP_PORT,
+            })?;
+        }
+
+        let mut replies = 0;
+        while socket.can_send() {
+            let (reply, endpoint) = match socket.recv() {
+                Ok((payload, endpoint)) => match self.reply_to(payload, now) {
+                    Some(reply) => (reply, endpoint),
+                    None => continue,
+                },
+                Err(Error::Exhausted) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            net_trace!("SNTP send reply to {}: {:?}", endpoint, reply);
+
+            let packet = socket.send(reply.buffer_len(), endpoint)?;
+            reply.emit_to_bytes(packet)?;
+            replies += 1;
+        }
+
+        Ok(replies)
+    }
+
+    /// Builds the reply to a request, or returns `None` if it is not a valid one.
+    pub(crate) fn reply_to(&self, data: &[u8], now: Timestamp) -> Option<Repr> {
+        let packet = Packet::new_checked(data).ok()?;
+        let version = packet.version();
+        if packet.protocol_mode() != ProtocolMode::Client
+            || !(MIN_EMIT_VERSION..=NTP_VERSION_4).contains(&version)
+        {
+            net_debug!("SNTP server discarding unexpected packet");
+            return None;
+        }
+        let request = Repr::parse(&packet).ok()?;
+
+        Some(Repr {
+            leap_indicator: LeapIndicator::NoWarning,
+            version,
+            protocol_mode: ProtocolMode::Server,
+            stratum: self.stratum,
+            poll_interval: request.poll_interval,
+            precision: self.precision,
+            root_delay: self.root_delay,
+            root_dispersion: self.root_dispersion,
+            ref_identifier: self.ref_identifier,
+            ref_timestamp: self.ref_timestamp.unwrap_or(now),
+            orig_timestamp: request.xmit_timestamp,
+            recv_timestamp: now,
+            xmit_timestamp: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::socket::UdpPacketMetadata;
+    use crate::net::wire::Ipv4Address;
+    use crate::servers::QueryMode;
+    use crate::wire::pack_flags;
+    use crate::wire::test::PACKET_BYTES;
+
+    #[test]
+    fn test_reply() {
+        let mut sockets_entries: [_; 1] = Default::default();
+        let mut sockets = SocketSet::new(&mut sockets_entries[..]);
+        let (mut rx_metadata, mut rx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let (mut tx_metadata, mut tx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let server = Server::new(
+            &mut sockets,
+            UdpSocketBuffer::new(&mut rx_metadata[..], &mut rx_storage[..]),
+            UdpSocketBuffer::new(&mut tx_metadata[..], &mut tx_storage[..]),
+        );
+
+        let mut request = Repr::parse_bytes(&PACKET_BYTES[..]).unwrap();
+        request.protocol_mode = ProtocolMode::Client;
+        request.version = 3;
+        let mut bytes = [0; 48];
+        request.emit_to_bytes(&mut bytes[..]).unwrap();
+
+        let now = Timestamp::from_secs_millis(3_800_000_000, 500);
+        let reply = server.reply_to(&bytes[..], now).unwrap();
+        assert_eq!(reply.protocol_mode, ProtocolMode::Server);
+        assert_eq!(reply.version, 3);
+        assert_eq!(reply.stratum, Stratum::Primary);
+        assert_eq!(reply.reference_code(), Some(*b"LOCL"));
+        assert_eq!(reply.orig_timestamp, request.xmit_timestamp);
+        assert_eq!(reply.recv_timestamp, now);
+        assert_eq!(reply.xmit_timestamp, now);
+
+        // Quality of the server
+        let mut server = server;
+        assert_eq!(
+            server.set_stratum(Stratum::Reserved(16)),
+            Err(SntpError::OutOfRange)
+        );
+        server.set_stratum(Stratum::Secondary(2)).unwrap();
+        server.set_ref_identifier([192, 168, 1, 1]);
+        server.set_root_delay(Duration::from_millis(500)).unwrap();
+        server.set_root_dispersion(Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            server.set_root_delay(Duration::from_secs(40_000)),
+            Err(SntpError::OutOfRange)
+        );
+        let reply = server.reply_to(&bytes[..], now).unwrap();
+        assert_eq!(reply.stratum, Stratum::Secondary(2));
+        assert_eq!(reply.ref_identifier, [192, 168, 1, 1]);
+        assert_eq!(reply.root_delay, 0x8000);
+        assert_eq!(reply.root_dispersion, 0x1_0000);
+
+        // Server replies are not answered
+        assert_eq!(server.reply_to(&PACKET_BYTES[..], now), None);
+
+        // NTPv1 requests can't be answered in kind
+        bytes[0] = pack_flags(LeapIndicator::NoWarning, 1, ProtocolMode::Client);
+        assert_eq!(server.reply_to(&bytes[..], now), None);
+    }
+
+    #[test]
+    fn test_relay() {
+        let mut sockets_entries: [_; 1] = Default::default();
+        let mut sockets = SocketSet::new(&mut sockets_entries[..]);
+        let (mut rx_metadata, mut rx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let (mut tx_metadata, mut tx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let mut upstream = Server::new(
+            &mut sockets,
+            UdpSocketBuffer::new(&mut rx_metadata[..], &mut rx_storage[..]),
+            UdpSocketBuffer::new(&mut tx_metadata[..], &mut tx_storage[..]),
+        );
+        upstream.set_root_delay(Duration::from_millis(500)).unwrap();
+        upstream
+            .set_root_dispersion(Duration::from_millis(5))
+            .unwrap();
+        let mut server = Server::from_handle(upstream.udp_handle);
+
+        let addr = IpAddress::v4(192, 168, 1, 1);
+        let mut client: Client = Client::without_socket(addr, Instant::from_secs(0));
+        assert_eq!(
+            server.relay(&client, Instant::from_secs(0)),
+            Err(SntpError::NotSynchronized)
+        );
+
+        // Exchange with a round trip of 10 ms
+        let mut request = [0; 48];
+        client
+            .poll_requests(Instant::from_secs(0), |bytes, _| {
+                request.copy_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        let now = Timestamp::from_secs_millis(3_800_000_000, 0);
+        let mut reply = [0; 48];
+        upstream
+            .reply_to(&request[..], now)
+            .unwrap()
+            .emit_to_bytes(&mut reply[..])
+            .unwrap();
+        let source = IpEndpoint::new(addr, SNTP_PORT);
+        let result = client
+            .process_response(&reply[..], source, Instant::from_millis(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.root_delay_micros(), 510_000);
+
+        server.relay(&client, Instant::from_millis(10)).unwrap();
+        let reply = server.reply_to(&request[..], now).unwrap();
+        assert_eq!(reply.stratum, Stratum::Secondary(2));
+        assert_eq!(reply.reference_ip(), Some(Ipv4Address([192, 168, 1, 1])));
+        assert_eq!(reply.root_delay, 0x0000_828f); // 510 ms
+        let distance = client.sync_distance(Instant::from_millis(10)).unwrap();
+        let dispersion = client.root_dispersion(Instant::from_millis(10)).unwrap();
+        assert_eq!(dispersion + Duration::from_millis(255), distance);
+        assert_eq!(
+            reply.root_dispersion,
+            duration_to_short(dispersion).unwrap()
+        );
+
+        // Upstream servers at the maximum stratum can't be relayed
+        upstream.set_stratum(Stratum::Secondary(15)).unwrap();
+        client.force_sync_override(Instant::from_secs(1));
+        client
+            .poll_requests(Instant::from_secs(1), |bytes, _| {
+                request.copy_from_slice(bytes);
+                Ok(())
+            })
+            .unwrap();
+        let mut reply = [0; 48];
+        upstream
+            .reply_to(&request[..], now)
+            .unwrap()
+            .emit_to_bytes(&mut reply[..])
+            .unwrap();
+        client
+            .process_response(&reply[..], source, Instant::from_secs(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            server.relay(&client, Instant::from_secs(1)),
+            Err(SntpError::OutOfRange)
+        );
+        assert_eq!(server.stratum, Stratum::Secondary(2));
+    }
+
+    #[test]
+    fn test_relay_concurrent() {
+        let mut sockets_entries: [_; 1] = Default::default();
+        let mut sockets = SocketSet::new(&mut sockets_entries[..]);
+        let (mut rx_metadata, mut rx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let (mut tx_metadata, mut tx_storage) = ([UdpPacketMetadata::EMPTY; 1], [0; 128]);
+        let upstream = Server::new(
+            &mut sockets,
+            UdpSocketBuffer::new(&mut rx_metadata[..], &mut rx_storage[..]),
+            UdpSocketBuffer::new(&mut tx_metadata[..], &mut tx_storage[..]),
+        );
+        let mut server = Server::from_handle(upstream.udp_handle);
+
+        let (addr, backup) = (IpAddress::v4(192, 168, 1, 1), IpAddress::v4(192, 168, 1, 2));
+        let mut client: Client = Client::without_socket(addr, Instant::from_secs(0));
+        client.add_server(backup).unwrap();
+        client.set_query_mode(QueryMode::Concurrent);
+
+        let mut requests = [(IpAddress::Unspecified, [0; 48]); 2];
+        let mut sent = 0;
+        client
+            .poll_requests(Instant::from_secs(0), |bytes, endpoint| {
+                requests[sent].0 = endpoint.addr;
+                requests[sent].1.copy_from_slice(bytes);
+                sent += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sent, 2);
+
+        // The backup reply has the lower offset, so it is selected although
+        // the first server is the active one
+        let mut result = None;
+        for (source, request) in &requests {
+            let secs = if *source == backup {
+                3_800_000_000
+            } else {
+                3_800_000_100
+            };
+            let mut reply = [0; 48];
+            upstream
+                .reply_to(&request[..], Timestamp::from_secs_millis(secs, 0))
+                .unwrap()
+                .emit_to_bytes(&mut reply[..])
+                .unwrap();
+            let source = IpEndpoint::new(*source, SNTP_PORT);
+            result = client
+                .process_response(&reply[..], source, Instant::from_millis(10))
+                .unwrap();
+        }
+        assert_eq!(result.unwrap().server(), backup);
+        assert_eq!(client.active_server(), Some(addr));
+
+        server.relay(&client, Instant::from_millis(10)).unwrap();
+        let reply = server
+            .reply_to(&requests[0].1[..], Timestamp::default())
+            .unwrap();
+        assert_eq!(reply.reference_ip(), Some(Ipv4Address([192, 168, 1, 2])));
+    }
+}
@@ -0,0 +1,287 @@
+//! Bookkeeping for the servers a client synchronizes with.
+
+use crate::client::SyncResult;
+use crate::net::{time::Instant, wire::IpAddress};
+use crate::wire::Timestamp;
+
+/// Maximum number of servers a client can be configured with.
+pub const MAX_SERVERS: usize = 4;
+
+/// Number of consecutive unanswered requests after which the client
+/// switches to the next server, with [`RetryPolicy::SameServer`].
+pub(crate) const FAILOVER_THRESHOLD: u8 = 3;
+
+/// How a client with several servers queries them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum QueryMode {
+    /// Requests are sent to a single server at a time, switching to the next
+    /// one when it stops answering or is blocked. This is the default.
+    Failover,
+    /// Requests are sent to all the usable servers at once, and the replies
+    /// received within a short window are combined by taking the median offset.
+    Concurrent,
+}
+
+impl Default for QueryMode {
+    fn default() -> Self {
+        QueryMode::Failover
+    }
+}
+
+/// Which server a request is retried with after a timeout, in [`QueryMode::Failover`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RetryPolicy {
+    /// Retry with the same server, switching to the next one only after three
+    /// consecutive unanswered requests. This is the default.
+    SameServer,
+    /// Retry with the next server as soon as a request goes unanswered.
+    NextServer,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::SameServer
+    }
+}
+
+/// The timestamps of a complete exchange with a server.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Exchange {
+    /// Time the request left the client (T1), as sent in the request.
+    pub client_transmit: Timestamp,
+    /// Time the request reached the server (T2).
+    pub server_receive: Timestamp,
+    /// Time the response left the server (T3).
+    pub server_transmit: Timestamp,
+    /// Local instant at which the response was received (T4).
+    pub client_receive: Instant,
+}
+
+/// The reason why a server is excluded from selection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BlockReason {
+    /// The server was blocked by the application.
+    Manual,
+    /// The server replied with a kiss-o'-death packet carrying the given
+    /// code (`DENY` or `RSTR`), asking the client to stop sending requests.
+    KissOfDeath([u8; 4]),
+    /// The address can't be the one of a unicast server, e.g. it is the
+    /// unspecified, the broadcast or a multicast address. Unblocking the
+    /// server overrides this.
+    InvalidAddress,
+}
+
+/// The reason why the client switched to another server, in [`QueryMode::Failover`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SwitchReason {
+    /// The previous server left too many requests unanswered.
+    Unreachable,
+    /// The previous server was blocked, by the application or by a kiss-o'-death.
+    Blocked(BlockReason),
+}
+
+/// A configured server.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ServerEntry {
+    /// Address of the server.
+    pub(crate) addr: IpAddress,
+    /// Why the server is blocked, if it is.
+    pub(crate) blocked: Option<BlockReason>,
+    /// Number of consecutive requests to this server that went unanswered.
+    pub(crate) failures: u8,
+    /// When a request was sent to this server in the current round, if it was.
+    pub(crate) sent_at: Option<Instant>,
+    /// Transmit timestamp (T1) of the request, until a valid reply is received.
+    pub(crate) request_timestamp: Option<Timestamp>,
+    /// Valid reply received in the current round, waiting to be combined.
+    pub(crate) reply: Option<SyncResult>,
+    /// Whether the last reply disagreed with the majority of the servers.
+    pub(crate) falseticker: bool,
+    /// Last exchange for which a valid reply was received.
+    pub(crate) previous: Option<Exchange>,
+}
+
+impl ServerEntry {
+    fn new(addr: IpAddress) -> ServerEntry {
+        ServerEntry {
+            addr,
+            blocked: if addr.is_unicast() {
+                None
+            } else {
+                Some(BlockReason::InvalidAddress)
+            },
+            failures: 0,
+            sent_at: None,
+            request_timestamp: None,
+            reply: None,
+            falseticker: false,
+            previous: None,
+        }
+    }
+
+    /// Returns `true` if a request was sent in the current round and no valid
+    /// reply has been received yet.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.request_timestamp.is_some()
+    }
+}
+
+/// A fixed-size list of servers, one of which is active at any given time.
+#[derive(Debug)]
+pub(crate) struct ServerList {
+    entries: [Option<ServerEntry>; MAX_SERVERS],
+    /// Index of the server requests are sent to.
+    active: usize,
+}
+
+impl ServerList {
+    /// Creates a list containing only the given server.
+    pub(crate) fn new(addr: IpAddress) -> ServerList {
+        let mut entries = [None; MAX_SERVERS];
+        entries[0] = Some(ServerEntry::new(addr));
+        ServerList { entries, active: 0 }
+    }
+
+    /// Adds a server to the list.
+    ///
+    /// Returns `false` if the list is full. Adding a server twice has no effect.
+    pub(crate) fn add(&mut self, addr: IpAddress) -> bool {
+        if self.get(addr).is_some() {
+            return true;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(slot) => {
+                *slot = Some(ServerEntry::new(addr));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the entry of the given server, if configured.
+    pub(crate) fn get(&self, addr: IpAddress) -> Option<&ServerEntry> {
+        self.iter().find(|entry| entry.addr == addr)
+    }
+
+    /// Returns the entry of the given server, if configured.
+    pub(crate) fn get_mut(&mut self, addr: IpAddress) -> Option<&mut ServerEntry> {
+        self.iter_mut().find(|entry| entry.addr == addr)
+    }
+
+    /// Returns the active server, even if it is blocked.
+    pub(crate) fn current(&self) -> Option<&ServerEntry> {
+        self.entries[self.active].as_ref()
+    }
+
+    /// Returns the active server, unless it is blocked.
+    pub(crate) fn active(&self) -> Option<&ServerEntry> {
+        self.entries[self.active]
+            .as_ref()
+            .filter(|entry| entry.blocked.is_none())
+    }
+
+    /// Returns an iterator over the configured servers.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &ServerEntry> {
+        self.entries.iter().filter_map(Option::as_ref)
+    }
+
+    /// Returns a mutable iterator over the configured servers.
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut ServerEntry> {
+        self.entries.iter_mut().filter_map(Option::as_mut)
+    }
+
+    /// Forgets the requests and replies of the current round.
+    pub(crate) fn clear_requests(&mut self) {
+        for entry in self.iter_mut() {
+            entry.sent_at = None;
+            entry.request_timestamp = None;
+            entry.reply = None;
+        }
+    }
+
+    /// Forgets everything known about the servers, keeping only their addresses.
+    pub(crate) fn reset(&mut self) {
+        for entry in self.iter_mut() {
+            // Keep the decision to use an invalid address anyway
+            let blocked = entry
+                .blocked
+                .filter(|&reason| reason == BlockReason::InvalidAddress);
+            *entry = ServerEntry {
+                blocked,
+                ..ServerEntry::new(entry.addr)
+            };
+        }
+        self.active = 0;
+    }
+
+    /// Selects the server the next request should be sent to.
+    ///
+    /// The active server is kept until it is blocked or `threshold` requests
+    /// in a row went unanswered, in which case the next usable server in the
+    /// list becomes active. Returns `None` if all servers are blocked.
+    pub(crate) fn select(&mut self, threshold: u8) -> Option<IpAddress> {
+        if let Some(entry) = self.active() {
+            if entry.failures < threshold {
+                return Some(entry.addr);
+            }
+        }
+
+        for i in 1..=MAX_SERVERS {
+            let idx = (self.active + i) % MAX_SERVERS;
+            if let Some(entry) = self.entries[idx].as_mut() {
+                if entry.blocked.is_none() {
+                    net_debug!("SNTP switching to server {}", entry.addr);
+                    entry.failures = 0;
+                    self.active = idx;
+                    return Some(entry.addr);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let (a, b) = (IpAddress::v4(10, 0, 0, 1), IpAddress::v4(10, 0, 0, 2));
+        let mut servers = ServerList::new(a);
+        assert!(servers.add(b));
+        assert_eq!(servers.select(FAILOVER_THRESHOLD), Some(a));
+
+        // Failover after too many failures
+        servers.get_mut(a).unwrap().failures = FAILOVER_THRESHOLD;
+        assert_eq!(servers.select(FAILOVER_THRESHOLD), Some(b));
+
+        // Blocked servers are skipped
+        servers.get_mut(a).unwrap().blocked = Some(BlockReason::Manual);
+        servers.get_mut(b).unwrap().failures = FAILOVER_THRESHOLD;
+        assert_eq!(servers.select(FAILOVER_THRESHOLD), Some(b));
+
+        servers.get_mut(b).unwrap().blocked = Some(BlockReason::Manual);
+        assert_eq!(servers.select(FAILOVER_THRESHOLD), None);
+
+        // Immediate failover
+        servers.get_mut(a).unwrap().blocked = None;
+        servers.get_mut(b).unwrap().blocked = None;
+        servers.get_mut(b).unwrap().failures = 0;
+        assert_eq!(servers.select(1), Some(b));
+        servers.get_mut(b).unwrap().failures = 1;
+        assert_eq!(servers.select(1), Some(a));
+    }
+
+    #[test]
+    fn test_add() {
+        let (a, b) = (IpAddress::v4(10, 0, 0, 1), IpAddress::v4(10, 0, 0, 2));
+        let mut servers = ServerList::new(a);
+        for i in 1..MAX_SERVERS {
+            assert!(servers.add(IpAddress::v4(10, 0, 1, i as u8)));
+        }
+        assert!(servers.add(a));
+        assert!(!servers.add(b));
+    }
+}
@@ -0,0 +1,42 @@
+//! Helpers shared by the client and the server to manage their UDP socket.
+
+use crate::client::MIN_BUFFER_LEN;
+use crate::error::{Result, SntpError};
+use crate::net::socket::{Socket, SocketHandle, SocketSet, UdpSocket};
+
+/// Ensures that `handle` refers to a UDP socket of `sockets` able to hold SNTP packets.
+///
+/// Returns `Err(SntpError::InvalidSocket)` if there is no such UDP socket, or
+/// the error of [`check_buffers`] otherwise.
+pub(crate) fn check_socket(sockets: &SocketSet, handle: SocketHandle) -> Result<()> {
+    match find_udp_socket(sockets, handle) {
+        Some(socket) => check_buffers(socket),
+        None => Err(SntpError::InvalidSocket),
+    }
+}
+
+/// Ensures that the socket buffers can hold an SNTP packet.
+pub(crate) fn check_buffers(socket: &UdpSocket) -> Result<()> {
+    if socket.packet_send_capacity() == 0 || socket.packet_recv_capacity() == 0 {
+        net_debug!("SNTP socket buffers without packet metadata");
+        return Err(SntpError::NoPacketMetadata);
+    }
+    if socket.payload_send_capacity() < MIN_BUFFER_LEN
+        || socket.payload_recv_capacity() < MIN_BUFFER_LEN
+    {
+        net_debug!("SNTP socket buffers smaller than {} bytes", MIN_BUFFER_LEN);
+        return Err(SntpError::BufferTooSmall);
+    }
+    Ok(())
+}
+
+/// Returns the UDP socket of `sockets` with the given handle, if any.
+fn find_udp_socket<'s, 'b, 'c>(
+    sockets: &'s SocketSet<'_, 'b, 'c>,
+    handle: SocketHandle,
+) -> Option<&'s UdpSocket<'b, 'c>> {
+    sockets.iter().find_map(|socket| match socket {
+        Socket::Udp(socket) if socket.handle() == handle => Some(socket),
+        _ => None,
+    })
+}
@@ -0,0 +1,50 @@
+/// Counters of the packets discarded by the client.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Stats {
+    /// Packets that can't be SNTP responses, e.g. too short or with an invalid stratum.
+    pub filtered: u32,
+    /// Packets coming from an address outside of the trusted sources.
+    pub untrusted_source: u32,
+    /// Responses not coming from the server the outstanding request was sent to.
+    pub wrong_source: u32,
+    /// Packets with an unexpected mode or an unsupported version. `poll` skips
+    /// them and reports each to the error hook.
+    pub invalid_header: u32,
+    /// Packets larger than the maximum packet length. `poll` skips them and
+    /// reports each to the error hook.
+    pub oversized: u32,
+    /// Client-mode requests of other hosts, received because the socket is
+    /// bound to the NTP port. These hint at a binding or topology problem.
+    pub client_requests: u32,
+    /// Responses whose originate timestamp doesn't match the outstanding request.
+    pub wrong_origin: u32,
+    /// Responses from servers reporting an unsynchronized clock.
+    pub unsynchronized: u32,
+    /// Responses with a leap indicator other than the allowed ones.
+    pub disallowed_leap: u32,
+    /// Responses too far from the time estimated by the client.
+    pub outside_window: u32,
+    /// Interleaved-mode responses, whose originate timestamp refers to the
+    /// previous request.
+    pub interleaved: u32,
+    /// Responses with a precision outside of -32..=10, with strict validation.
+    pub invalid_precision: u32,
+    /// Responses with a poll interval outside of 3..=17, with strict validation.
+    pub invalid_poll: u32,
+    /// Responses from primary servers whose reference identifier isn't an
+    /// ASCII code, with strict validation.
+    pub invalid_reference: u32,
+    /// Responses from secondary servers whose reference identifier is their
+    /// own address, with loop detection.
+    pub self_reference: u32,
+    /// Responses whose reference timestamp is later than their transmit
+    /// timestamp, with loop detection.
+    pub future_reference: u32,
+    /// Responses without a valid MAC, when authentication is required.
+    pub unauthenticated: u32,
+    /// Responses whose server processing delay is negative or larger than
+    /// the maximum set with `set_max_processing_delay`.
+    pub processing_delay_rejected: u32,
+    /// Responses from servers that synchronized too long ago, or never did.
+    pub stale_server: u32,
+}
@@ -0,0 +1,222 @@
+//! Helpers for applications running on a hosted platform.
+//!
+//! These block until a synchronization completes, e.g. for command-line tools
+//! or integration tests: [`query_once`] drives a [`Client`] over a
+//! `std::net::UdpSocket` without `smoltcp` interfaces, while [`poll_blocking`]
+//! drives a client over a `smoltcp` interface. Real applications should call
+//! [`Client::poll`] from their event loop instead.
+//!
+//! [`query_once`]: fn.query_once.html
+//! [`poll_blocking`]: fn.poll_blocking.html
+//! [`Client`]: ../struct.Client.html
+//! [`Client::poll`]: ../struct.Client.html#method.poll
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::string::ToString;
+use std::thread;
+use std::time::{Duration, Instant as StdInstant};
+
+use crate::client::{Client, SyncResult, SNTP_PORT};
+use crate::error::{Result, SntpError};
+use crate::net::socket::SocketSet;
+use crate::net::time::{Duration as NetDuration, Instant};
+use crate::net::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use crate::wire::Stratum;
+
+/// Synchronizes once with the SNTP server at `server` and `port`.
+///
+/// `server` is a host name or an IP address, resolved with the system
+/// resolver: the first IPv4 address found is used. A single request is sent,
+/// and the first valid response received within `timeout` is returned.
+///
+/// Returns an error of kind `TimedOut` if no valid response arrives in time,
+/// `InvalidInput` if `server` has no IPv4 address, and `Other` if the server
+/// refuses to answer, e.g. with a kiss-o'-death packet.
+pub fn query_once(server: &str, port: u16, timeout: Duration) -> io::Result<SyncResult> {
+    let addr = (server, port)
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no IPv4 address"))?;
+    let server_addr = match addr.ip() {
+        IpAddr::V4(ip) => IpAddress::Ipv4(Ipv4Address(ip.octets())),
+        IpAddr::V6(_) => unreachable!(),
+    };
+
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    let mut client: Client = Client::without_socket(server_addr, Instant::now());
+
+    let mut sent = Ok(());
+    client
+        .poll_requests(Instant::now(), |bytes, _| {
+            sent = socket.send_to(bytes, addr).map(|_| ());
+            Ok(())
+        })
+        .map_err(to_io_error)?;
+    sent?;
+
+    let deadline = StdInstant::now() + timeout;
+    let mut buf = [0; 128];
+    loop {
+        let remaining = deadline
+            .checked_duration_since(StdInstant::now())
+            .filter(|remaining| *remaining > Duration::from_millis(0))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no response"))?;
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, source) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        if source != addr {
+            continue;
+        }
+
+        // The client expects responses from the standard port
+        let source = IpEndpoint::new(server_addr, SNTP_PORT);
+        if let Some(result) = client
+            .process_response(&buf[..len], source, Instant::now())
+            .map_err(to_io_error)?
+        {
+            return Ok(result);
+        }
+        if let Some(repr) = client.last_repr() {
+            if repr.stratum == Stratum::KissOfDeath {
+                return Err(to_io_error(SntpError::KissOfDeath(repr.ref_identifier)));
+            }
+        }
+    }
+}
+
+/// Longest sleep between two polls of the interface by [`poll_blocking`].
+///
+/// [`poll_blocking`]: fn.poll_blocking.html
+const MAX_POLL_SLEEP: NetDuration = NetDuration { millis: 1 };
+
+/// Polls the interface and `client` until a synchronization completes.
+///
+/// `poll_iface` is called with the socket set and the current time before each
+/// poll of the client, and should poll the `smoltcp` interface, e.g. with
+/// `|sockets, now| { iface.poll(sockets, now).ok(); }`. Between polls, the
+/// thread sleeps until the next request is due, for at most a millisecond so
+/// that responses are timestamped promptly.
+///
+/// Returns the first result of [`Client::poll`], or `Err(SntpError::TimedOut)`
+/// if none was obtained within `timeout`. Errors returned by the client are
+/// returned right away.
+///
+/// This busy-waits, and is only meant for quick scripts and tests: real
+/// applications should call [`Client::poll`] from their event loop.
+///
+/// [`Client::poll`]: ../struct.Client.html#method.poll
+pub fn poll_blocking<F, const N: usize, C>(
+    client: &mut Client<N, C>,
+    sockets: &mut SocketSet,
+    timeout: Duration,
+    mut poll_iface: F,
+) -> Result<SyncResult>
+where
+    F: FnMut(&mut SocketSet, Instant),
+{
+    let deadline = StdInstant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        poll_iface(sockets, now);
+        if let Some(result) = client.poll(sockets, now)? {
+            return Ok(result);
+        }
+
+        let remaining = deadline
+            .checked_duration_since(StdInstant::now())
+            .filter(|remaining| *remaining > Duration::from_millis(0))
+            .ok_or(SntpError::TimedOut)?;
+        let sleep = client.next_poll(now).min(MAX_POLL_SLEEP);
+        thread::sleep(remaining.min(Duration::from_millis(sleep.total_millis())));
+    }
+}
+
+/// Converts a client error to an I/O error.
+fn to_io_error(err: SntpError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::{LeapIndicator, ProtocolMode, Repr, Timestamp};
+
+    /// Answers a single request on a local socket, with the given stratum.
+    fn serve_once(stratum: Stratum) -> u16 {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let mut buf = [0; 128];
+            let (len, client) = socket.recv_from(&mut buf).unwrap();
+            let request = Repr::parse_bytes(&buf[..len]).unwrap();
+            let now = Timestamp::from_unix_micros(Instant::now().total_millis() * 1_000);
+            let reply = Repr {
+                leap_indicator: LeapIndicator::NoWarning,
+                protocol_mode: ProtocolMode::Server,
+                stratum,
+                ref_identifier: *b"RATE",
+                orig_timestamp: request.xmit_timestamp,
+                recv_timestamp: now,
+                xmit_timestamp: now,
+                ..request
+            };
+            let len = reply.emit_to_bytes(&mut buf[..]).unwrap();
+            socket.send_to(&buf[..len], client).unwrap();
+        });
+        port
+    }
+
+    #[test]
+    fn test_query_once() {
+        let port = serve_once(Stratum::Primary);
+        let result = query_once("localhost", port, Duration::from_secs(5)).unwrap();
+        assert!(result.offset_micros().abs() < 1_000_000);
+
+        let port = serve_once(Stratum::KissOfDeath);
+        let err = query_once("127.0.0.1", port, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+
+        // Nothing listens on the port anymore
+        let err = query_once("127.0.0.1", port, Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused
+        ));
+    }
+
+    #[test]
+    fn test_poll_blocking() {
+        use crate::net::socket::{Socket, UdpPacketMetadata, UdpSocketBuffer};
+        use std::vec;
+
+        let mut sockets = SocketSet::new(vec![]);
+        let server = IpAddress::v4(127, 0, 0, 1);
+        let mut client: Client = Client::new(
+            &mut sockets,
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; 128]),
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; 128]),
+            server,
+            Instant::now(),
+        );
+
+        // Without an interface, the request never leaves the socket
+        let mut polls = 0;
+        let result = poll_blocking(
+            &mut client,
+            &mut sockets,
+            Duration::from_millis(20),
+            |_, _| polls += 1,
+        );
+        assert_eq!(result, Err(SntpError::TimedOut));
+        assert!(polls > 1);
+        // The request is still queued
+        assert!(sockets
+            .iter()
+            .all(|socket| matches!(socket, Socket::Udp(socket) if !socket.can_send())));
+    }
+}